@@ -0,0 +1,7 @@
+use rand::Rng;
+use search_stack_exchange::Embedding;
+
+/// Each component drawn uniformly from `-1..1`.
+pub fn random_embedding(rng: &mut impl Rng) -> Embedding {
+    Embedding(std::array::from_fn(|_| rng.gen_range(-1f32..1.)))
+}
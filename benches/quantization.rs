@@ -0,0 +1,90 @@
+//! Compares speed and recall of searching quantized embeddings against the exact search of
+//! [`Embeddings::find_most_similar`].
+
+mod common;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use search_stack_exchange::{
+    text_hash, BinaryEmbeddings, Embedding, Embeddings, Int8Embeddings, Record, EMBEDDING_SIZE,
+};
+
+use self::common::random_embedding;
+
+const NUM_EMBEDDINGS: usize = 100_000;
+const NUM_QUERIES: usize = 200;
+/// Candidates found with binary embeddings, which are rescored with the originals.
+const NUM_CANDIDATES: usize = 100;
+
+/// Queries are noisy copies of stored embeddings, so each query has a clear best match, which is
+/// still not trivial to find.
+fn synthetic_data(rng: &mut impl Rng) -> (Embeddings, Vec<Embedding>) {
+    let records = (0..NUM_EMBEDDINGS)
+        .map(|id| Record {
+            id: id as u64,
+            text_hash: text_hash(""),
+            embedding: random_embedding(rng),
+        })
+        .collect::<Vec<_>>();
+    let queries = (0..NUM_QUERIES)
+        .map(|_| {
+            let original = records[rng.gen_range(0..NUM_EMBEDDINGS)].embedding;
+            Embedding(original.0.map(|value| value + rng.gen_range(-1.5f32..1.5)))
+        })
+        .collect();
+    (Embeddings::from_vec(records), queries)
+}
+
+/// Share of queries for which `search` finds the same embedding as the exact search.
+fn recall(
+    exact: &[Option<usize>],
+    queries: &[Embedding],
+    search: impl Fn(&Embedding) -> Option<usize>,
+) -> f64 {
+    let hits = queries
+        .iter()
+        .zip(exact)
+        .filter(|(query, expected)| search(query) == **expected)
+        .count();
+    hits as f64 / queries.len() as f64
+}
+
+fn quantization(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(42);
+    let (embeddings, queries) = synthetic_data(&mut rng);
+    let int8 = Int8Embeddings::from_embeddings(&embeddings);
+    let binary = BinaryEmbeddings::from_embeddings(&embeddings);
+
+    let exact: Vec<_> = queries
+        .iter()
+        .map(|query| embeddings.find_most_similar(query).unwrap())
+        .collect();
+    println!(
+        "Recall int8: {:.3}, binary: {:.3}, binary rescored: {:.3}, {} dimensions",
+        recall(&exact, &queries, |query| int8.find_most_similar(query)),
+        recall(&exact, &queries, |query| binary.find_most_similar(query)),
+        recall(&exact, &queries, |query| binary.find_most_similar_rescored(
+            query,
+            NUM_CANDIDATES,
+            &embeddings
+        )),
+        EMBEDDING_SIZE,
+    );
+
+    let query = &queries[0];
+    c.bench_function("exact", |b| {
+        b.iter(|| embeddings.find_most_similar(black_box(query)))
+    });
+    c.bench_function("int8", |b| {
+        b.iter(|| int8.find_most_similar(black_box(query)))
+    });
+    c.bench_function("binary", |b| {
+        b.iter(|| binary.find_most_similar(black_box(query)))
+    });
+    c.bench_function("binary rescored", |b| {
+        b.iter(|| binary.find_most_similar_rescored(black_box(query), NUM_CANDIDATES, &embeddings))
+    });
+}
+
+criterion_group!(benches, quantization);
+criterion_main!(benches);
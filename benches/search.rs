@@ -0,0 +1,40 @@
+//! Tracks how many queries per second [`Embeddings::find_top_k`] answers on a synthetic set of a
+//! million embeddings.
+
+mod common;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rand::{rngs::StdRng, SeedableRng};
+use search_stack_exchange::{text_hash, Embeddings, Record};
+
+use self::common::random_embedding;
+
+const NUM_EMBEDDINGS: usize = 1_000_000;
+
+fn search(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(42);
+    let embeddings = Embeddings::from_vec(
+        (0..NUM_EMBEDDINGS)
+            .map(|id| Record {
+                id: id as u64,
+                text_hash: text_hash(""),
+                embedding: random_embedding(&mut rng),
+            })
+            .collect(),
+    );
+    let query = random_embedding(&mut rng);
+
+    let mut group = c.benchmark_group("million embeddings");
+    // Reports queries per second
+    group.throughput(Throughput::Elements(1));
+    group.sample_size(20);
+    for k in [1, 10, 100] {
+        group.bench_function(format!("top {k}"), |b| {
+            b.iter(|| embeddings.find_top_k(black_box(&query), k).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, search);
+criterion_main!(benches);
@@ -0,0 +1,97 @@
+use std::path::Path;
+
+use crate::{strip_html, Error, Post, PostReader};
+
+/// An answer to a question, as plain text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answer {
+    /// Post id of the answer
+    pub id: u64,
+    /// Upvotes minus downvotes
+    pub score: i64,
+    /// Whether the author of the question accepted this answer
+    pub accepted: bool,
+    /// Body of the answer without HTML markup
+    pub text: String,
+}
+
+/// Answers to the question with the post id `question_id`, best first. The accepted answer comes
+/// first, the others follow by score, ties ordered by id. Empty if the question has not been
+/// answered, or there is no such question.
+pub fn read_answers(posts_xml: &Path, question_id: u64) -> Result<Vec<Answer>, Error> {
+    let mut accepted_answer_id = None;
+    let mut answers = Vec::new();
+    let mut reader = PostReader::new(posts_xml)?;
+    while let Some(post) = reader.next_post()? {
+        match post {
+            Post::Question {
+                id,
+                accepted_answer_id: accepted,
+                ..
+            } if id == question_id => accepted_answer_id = accepted,
+            Post::Answer {
+                id,
+                parent_id,
+                body,
+                score,
+            } if parent_id == question_id => answers.push(Answer {
+                id,
+                score,
+                accepted: false,
+                text: strip_html(&body),
+            }),
+            _ => (),
+        }
+    }
+    // Answers may precede their question in the file, so they learn whether they are accepted
+    // only after reading all of it.
+    for answer in &mut answers {
+        answer.accepted = accepted_answer_id == Some(answer.id);
+    }
+    rank_answers(&mut answers);
+    Ok(answers)
+}
+
+/// Orders answers best first, see [`read_answers`].
+fn rank_answers(answers: &mut [Answer]) {
+    answers.sort_by(|a, b| {
+        b.accepted
+            .cmp(&a.accepted)
+            .then(b.score.cmp(&a.score))
+            .then(a.id.cmp(&b.id))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rank_answers, read_answers, Answer};
+
+    #[test]
+    fn accepted_answer_first() {
+        let answers = read_answers("./tests/small-posts.xml".as_ref(), 2).unwrap();
+
+        let ids: Vec<_> = answers.iter().map(|answer| answer.id).collect();
+        assert_eq!(vec![5, 4, 10, 6], ids);
+        assert!(answers[0].accepted);
+        assert_eq!(22, answers[0].score);
+        assert!(answers[0]
+            .text
+            .starts_with("There is very little information about safety available"));
+    }
+
+    #[test]
+    fn highest_score_first_without_accepted_answer() {
+        let answer = |id, score| Answer {
+            id,
+            score,
+            accepted: false,
+            text: String::new(),
+        };
+        let mut answers = vec![answer(1, 3), answer(2, 7), answer(3, 7)];
+
+        rank_answers(&mut answers);
+
+        let ids: Vec<_> = answers.iter().map(|answer| answer.id).collect();
+        assert_eq!(vec![2, 3, 1], ids);
+    }
+}
@@ -0,0 +1,179 @@
+use serde::Deserialize;
+
+use crate::Document;
+
+/// Weights of the signals blended into the final score of a question. Every signal except the
+/// similarity is scaled to the range from 0 to 1 (-1 for negative scores), so weights of different
+/// signals are comparable. Can be read from JSON, e.g. `{"score": 0.2, "recency": 0.1}`. Missing
+/// weights keep their default.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BoostWeights {
+    /// Weight of the score of the search, e.g. the similarity to the query
+    pub similarity: f32,
+    /// Weight of upvotes minus downvotes, scaled logarithmically
+    pub score: f32,
+    /// Weight of the number of views, scaled logarithmically
+    pub views: f32,
+    /// Weight of the number of answers, scaled logarithmically
+    pub answers: f32,
+    /// Weight added for questions with an accepted answer
+    pub accepted: f32,
+    /// Weight of the recency. Questions asked today count fully, older ones decay exponentially.
+    pub recency: f32,
+    /// Age in days at which the recency of a question has decayed to one half
+    pub half_life_days: f32,
+}
+
+impl Default for BoostWeights {
+    fn default() -> Self {
+        Self {
+            similarity: 1.,
+            score: 0.1,
+            views: 0.05,
+            answers: 0.05,
+            accepted: 0.1,
+            recency: 0.,
+            half_life_days: 365.,
+        }
+    }
+}
+
+/// Contribution of each signal to the final score of a question, i.e. the weighted signals.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BoostComponents {
+    pub similarity: f32,
+    pub score: f32,
+    pub views: f32,
+    pub answers: f32,
+    pub accepted: f32,
+    pub recency: f32,
+}
+
+impl BoostComponents {
+    /// Final score of the question
+    pub fn total(&self) -> f32 {
+        self.similarity + self.score + self.views + self.answers + self.accepted + self.recency
+    }
+}
+
+impl BoostWeights {
+    /// Blends the `similarity` of a question found by a search with its metadata. `today` is the
+    /// current day, counted in days since the first of January 1970, to tell the age of the
+    /// question. Questions without a creation date do not gain any recency.
+    pub fn explain(&self, similarity: f32, document: &Document, today: i64) -> BoostComponents {
+        let recency = day_of(&document.creation_date).map_or(0., |day| {
+            let age = (today - day).max(0) as f32;
+            0.5f32.powf(age / self.half_life_days)
+        });
+        BoostComponents {
+            similarity: self.similarity * similarity,
+            score: self.score * saturate(document.score as f32),
+            views: self.views * saturate(document.view_count as f32),
+            answers: self.answers * saturate(document.answer_count as f32),
+            accepted: if document.answered { self.accepted } else { 0. },
+            recency: self.recency * recency,
+        }
+    }
+
+    /// Reorders a `ranking`, given as tuples of post id and score, by the blended score. `document`
+    /// returns the metadata for a post id. Returns tuples of post id and components, best first.
+    /// Ties are broken in favour of the lower post id.
+    pub fn rank<'d>(
+        &self,
+        ranking: &[(u64, f32)],
+        document: impl Fn(u64) -> &'d Document,
+        today: i64,
+    ) -> Vec<(u64, BoostComponents)> {
+        let mut boosted: Vec<_> = ranking
+            .iter()
+            .map(|&(id, score)| (id, self.explain(score, document(id), today)))
+            .collect();
+        boosted
+            .sort_by(|(a_id, a), (b_id, b)| b.total().total_cmp(&a.total()).then(a_id.cmp(b_id)));
+        boosted
+    }
+}
+
+/// Scales counts logarithmically into `[0, 1)`, so the thousandth vote counts much less than the
+/// first. Negative counts are scaled the same way into `(-1, 0]`.
+fn saturate(count: f32) -> f32 {
+    let log = count.abs().ln_1p();
+    (log / (1. + log)).copysign(count)
+}
+
+/// Day of an ISO 8601 date like `2016-01-12T18:45:19.963`, counted in days since the first of
+/// January 1970. `None` if it does not start with `YYYY-MM-DD`.
+fn day_of(date: &str) -> Option<i64> {
+    let year: i64 = date.get(0..4)?.parse().ok()?;
+    let month: i64 = date.get(5..7)?.parse().ok()?;
+    let day: i64 = date.get(8..10)?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // See <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>. Years start in
+    // March, so the leap day is the last day of a year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{day_of, BoostWeights};
+    use crate::{Chunking, Document};
+
+    fn document(id: u64, score: i64, answered: bool) -> Document {
+        Document {
+            score,
+            answered,
+            creation_date: "2020-01-01T00:00:00.000".to_owned(),
+            ..Document::new(id, "Title".to_owned(), "", Chunking::whole())
+        }
+    }
+
+    #[test]
+    fn popular_answered_question_overtakes_slightly_more_similar_one() {
+        let documents = [document(1, 0, false), document(2, 300, true)];
+        let ranking = [(1, 0.80), (2, 0.75)];
+
+        let boosted = BoostWeights::default().rank(&ranking, |id| &documents[id as usize - 1], 0);
+
+        assert_eq!(2, boosted[0].0);
+        assert_eq!(0.1, boosted[0].1.accepted);
+        assert_eq!(0., boosted[1].1.score);
+    }
+
+    #[test]
+    fn recency_halves_after_half_life() {
+        let weights = BoostWeights {
+            recency: 1.,
+            half_life_days: 10.,
+            ..BoostWeights::default()
+        };
+        let today = day_of("2020-01-11").unwrap();
+
+        let components = weights.explain(0., &document(1, 0, false), today);
+
+        assert!((components.recency - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn days_since_unix_epoch() {
+        assert_eq!(Some(0), day_of("1970-01-01"));
+        assert_eq!(Some(18_321), day_of("2020-02-29T12:00:00.000"));
+        assert_eq!(None, day_of("2020"));
+    }
+
+    #[test]
+    fn read_weights_from_json() {
+        let weights: BoostWeights = serde_json::from_str(r#"{"score": 0.2}"#).unwrap();
+
+        assert_eq!(0.2, weights.score);
+        assert_eq!(BoostWeights::default().similarity, weights.similarity);
+        assert!(serde_json::from_str::<BoostWeights>(r#"{"votes": 1}"#).is_err());
+    }
+}
@@ -0,0 +1,611 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    embedding::{fnv1a, Record, Representation, FNV_OFFSET_BASIS, RECORD_SIZE},
+    text_hash, Embeddings, Error,
+};
+
+/// First bytes of every embedding cache file.
+const MAGIC: &[u8; 8] = b"SSEEMBED";
+/// Increment this, whenever the layout of the cache file changes. Version 3 stores normalized
+/// embeddings. Version 4 stores the representation of queries.
+const FORMAT_VERSION: u32 = 4;
+/// Size of the header at the start of each cache file in bytes.
+///
+/// | Offset | Size | Content                                 |
+/// |--------|------|-----------------------------------------|
+/// | 0      | 8    | Magic bytes `SSEEMBED`                  |
+/// | 8      | 4    | Format version                          |
+/// | 12     | 4    | Dimension of the embeddings             |
+/// | 16     | 8    | Number of embeddings in the file        |
+/// | 24     | 8    | Hash over the embedded texts            |
+/// | 32     | 1    | Representation of the embeddings        |
+/// | 33     | 1    | Representation of queries               |
+/// | 34     | 1    | Length of the model name                |
+/// | 35     | 29   | Model name, padded with zeroes          |
+///
+/// All numbers are little endian.
+const HEADER_SIZE: u64 = 64;
+/// Offset of the embedding count within the header.
+const COUNT_OFFSET: u64 = 16;
+/// Maximum number of bytes available to store the model name.
+const MAX_MODEL_LEN: usize = 29;
+/// Size of a single record in the cache file in bytes.
+const RECORD_LEN: u64 = RECORD_SIZE as u64;
+
+/// Describes the embeddings stored in a cache. A cache is only used, if its metadata matches the
+/// metadata of the embeddings requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheMetadata {
+    /// Name of the model which computed the embeddings.
+    pub model: String,
+    /// Representation of the stored embeddings.
+    pub representation: Representation,
+    /// Representation queries compared with the stored embeddings must use. `Query` if the
+    /// stored embeddings are `Document`s, `Symmetric` if they are `Symmetric`.
+    pub query_representation: Representation,
+    /// Number of dimensions of each embedding.
+    pub dimension: u32,
+    /// Hash over all the posts which are embedded. See [`source_hash`].
+    pub source_hash: u64,
+}
+
+impl CacheMetadata {
+    fn to_header(&self, count: u64) -> Result<[u8; HEADER_SIZE as usize], Error> {
+        let model = self.model.as_bytes();
+        if model.len() > MAX_MODEL_LEN {
+            return Err(Error::incompatible_cache(format!(
+                "Model name '{}' is too long to be stored in the cache",
+                self.model
+            )));
+        }
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&self.dimension.to_le_bytes());
+        header[16..24].copy_from_slice(&count.to_le_bytes());
+        header[24..32].copy_from_slice(&self.source_hash.to_le_bytes());
+        header[32] = representation_to_byte(self.representation);
+        header[33] = representation_to_byte(self.query_representation);
+        header[34] = model.len() as u8;
+        header[35..35 + model.len()].copy_from_slice(model);
+        Ok(header)
+    }
+
+    /// Parses the header of a cache file. Returns the metadata and the number of embeddings stored.
+    fn from_header(header: &[u8; HEADER_SIZE as usize]) -> Result<(Self, u64), Error> {
+        if &header[0..8] != MAGIC {
+            return Err(Error::incompatible_cache(
+                "File is not an embedding cache. Magic bytes are missing.",
+            ));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(Error::incompatible_cache(format!(
+                "Cache has format version {version}, but version {FORMAT_VERSION} is supported."
+            )));
+        }
+        let dimension = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let count = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let source_hash = u64::from_le_bytes(header[24..32].try_into().unwrap());
+        let representation = representation_from_byte(header[32]).ok_or_else(|| {
+            Error::incompatible_cache(format!("Unknown representation {}", header[32]))
+        })?;
+        let query_representation = representation_from_byte(header[33]).ok_or_else(|| {
+            Error::incompatible_cache(format!("Unknown query representation {}", header[33]))
+        })?;
+        let model_len = (header[34] as usize).min(MAX_MODEL_LEN);
+        let model = String::from_utf8_lossy(&header[35..35 + model_len]).into_owned();
+        let metadata = CacheMetadata {
+            model,
+            representation,
+            query_representation,
+            dimension,
+            source_hash,
+        };
+        Ok((metadata, count))
+    }
+
+    /// Describes the first difference to `expected`, which prevents reusing any of the embeddings.
+    /// Differences in the source are not reported, since embeddings of unchanged posts can still
+    /// be reused.
+    fn mismatch(&self, expected: &CacheMetadata) -> Option<String> {
+        if self.model != expected.model {
+            Some(format!(
+                "Cache has been computed with model '{}', but model '{}' is requested.",
+                self.model, expected.model
+            ))
+        } else if self.representation != expected.representation {
+            Some(format!(
+                "Cache holds {:?} embeddings, but {:?} embeddings are requested.",
+                self.representation, expected.representation
+            ))
+        } else if self.query_representation != expected.query_representation {
+            Some(format!(
+                "Cache holds embeddings for {:?} queries, but {:?} queries are requested.",
+                self.query_representation, expected.query_representation
+            ))
+        } else if self.dimension != expected.dimension {
+            Some(format!(
+                "Cache holds embeddings with {} dimensions, but {} dimensions are requested.",
+                self.dimension, expected.dimension
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+fn representation_to_byte(representation: Representation) -> u8 {
+    match representation {
+        Representation::Symmetric => 0,
+        Representation::Document => 1,
+        Representation::Query => 2,
+    }
+}
+
+fn representation_from_byte(byte: u8) -> Option<Representation> {
+    match byte {
+        0 => Some(Representation::Symmetric),
+        1 => Some(Representation::Document),
+        2 => Some(Representation::Query),
+        _ => None,
+    }
+}
+
+/// Hash identifying the posts which are embedded, each given as a tuple of post id and text.
+/// Changes if posts are added, removed, reordered or edited.
+pub fn source_hash<'a>(documents: impl IntoIterator<Item = (u64, &'a str)>) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for (id, text) in documents {
+        hash = fnv1a(hash, &id.to_le_bytes());
+        // Prefix each text with its length, so ["ab", "c"] and ["a", "bc"] differ.
+        hash = fnv1a(hash, &(text.len() as u64).to_le_bytes());
+        hash = fnv1a(hash, text.as_bytes());
+    }
+    hash
+}
+
+/// Embeddings persisted on disk. Embeddings are appended as soon as they arrive from the API, so
+/// an interrupted run can continue where it stopped, rather than paying for the same embeddings
+/// twice.
+pub struct EmbeddingCache {
+    file: File,
+    /// Progress marker. Number of embeddings completely written to the cache file. Mirrors the
+    /// count in the file header.
+    len: usize,
+}
+
+impl EmbeddingCache {
+    /// Opens the cache file at `path`, or creates it, if it does not exist yet. Fails if the
+    /// existing cache has been created for different embeddings than described by `metadata`. If
+    /// only the posts differ [`Error::CacheOutdated`] is returned and [`Self::migrate`] can be
+    /// used to reuse the embeddings of unchanged posts. If only the representations differ
+    /// [`Error::RepresentationChanged`] is returned and [`Self::recreate`] replaces the cache.
+    /// Embeddings written after the last update of the count in the header, e.g. by a process
+    /// killed in the middle of a write, are discarded.
+    pub fn open(path: &Path, metadata: &CacheMetadata) -> Result<Self, Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(Error::CacheIo)?;
+        let file_len = file.metadata().map_err(Error::CacheIo)?.len();
+        let len = if file_len == 0 {
+            file.write_all(&metadata.to_header(0)?)
+                .map_err(Error::CacheIo)?;
+            0
+        } else {
+            let (stored, count) = read_header(&mut file, path, metadata)?;
+            if stored.source_hash != metadata.source_hash {
+                return Err(Error::CacheOutdated);
+            }
+            if file_len < HEADER_SIZE + count * RECORD_LEN {
+                return Err(Error::incompatible_cache(
+                    "Cache file is shorter than its header claims.",
+                ));
+            }
+            file.set_len(HEADER_SIZE + count * RECORD_LEN)
+                .map_err(Error::CacheIo)?;
+            count
+        };
+        file.seek(SeekFrom::End(0)).map_err(Error::CacheIo)?;
+        Ok(Self {
+            file,
+            len: len as usize,
+        })
+    }
+
+    /// Replaces an outdated cache at `path` with one for `metadata`. Embeddings of posts in
+    /// `documents`, which did not change since the old cache has been written, are kept. All
+    /// others are dropped. Each document is a tuple of post id and embedded text.
+    pub fn migrate(
+        path: &Path,
+        metadata: &CacheMetadata,
+        documents: &[(u64, &str)],
+    ) -> Result<Self, Error> {
+        let mut old = File::open(path).map_err(Error::CacheIo)?;
+        let (_, count) = read_header(&mut old, path, metadata)?;
+        let wanted: HashSet<_> = documents
+            .iter()
+            .map(|&(id, text)| (id, text_hash(text)))
+            .collect();
+
+        let temporary = temporary_path(path);
+        let mut new = BufWriter::new(File::create(&temporary).map_err(Error::CacheIo)?);
+        let kept = copy_wanted_records(&mut BufReader::new(old), &mut new, count, &wanted)
+            .map_err(Error::CacheIo)?;
+        let mut new = new
+            .into_inner()
+            .map_err(|error| Error::CacheIo(error.into_error()))?;
+        new.seek(SeekFrom::Start(0)).map_err(Error::CacheIo)?;
+        new.write_all(&metadata.to_header(kept)?)
+            .map_err(Error::CacheIo)?;
+        drop(new);
+        // Replace the old cache only after the new one is complete.
+        fs::rename(&temporary, path).map_err(Error::CacheIo)?;
+        Self::open(path, metadata)
+    }
+
+    /// Replaces the cache at `path` with an empty one for `metadata`, e.g. because the
+    /// representation of the embeddings changed. None of the old embeddings can be reused. The
+    /// old cache is kept until the new one is complete.
+    pub fn recreate(path: &Path, metadata: &CacheMetadata) -> Result<Self, Error> {
+        let temporary = temporary_path(path);
+        fs::write(&temporary, metadata.to_header(0)?).map_err(Error::CacheIo)?;
+        fs::rename(&temporary, path).map_err(Error::CacheIo)?;
+        Self::open(path, metadata)
+    }
+
+    /// Number of embeddings already stored in the cache.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// All documents for which the cache does not hold an embedding yet. Each document is a tuple
+    /// of post id and embedded text.
+    pub fn missing<'a>(
+        &mut self,
+        documents: &[(u64, &'a str)],
+    ) -> Result<Vec<(u64, &'a str)>, Error> {
+        // Only post id and text hash of each record are needed, so the embeddings are mapped
+        // rather than read into memory.
+        // Safety: The mapping is dropped before this method returns, so nothing is appended to
+        // the file while it exists.
+        let mapped = unsafe { Embeddings::map(&self.file, HEADER_SIZE as usize, self.len) }
+            .map_err(Error::CacheIo)?;
+        let cached: HashSet<_> = mapped
+            .records()
+            .iter()
+            .map(|record| (record.id, record.text_hash))
+            .collect();
+        Ok(documents
+            .iter()
+            .filter(|&&(id, text)| !cached.contains(&(id, text_hash(text))))
+            .copied()
+            .collect())
+    }
+
+    /// Appends a record to the end of the cache, with its embedding normalized. The record is
+    /// handed to the operating system before this method returns, so it survives the process
+    /// dying.
+    pub fn append(&mut self, record: &Record) -> Result<(), Error> {
+        self.append_io(record).map_err(Error::CacheIo)
+    }
+
+    fn append_io(&mut self, record: &Record) -> Result<(), io::Error> {
+        let mut buf = [0u8; RECORD_SIZE];
+        let record = Record {
+            embedding: record.embedding.normalize(),
+            ..*record
+        };
+        record.write_to_bytes(&mut buf);
+        self.file.write_all(&buf)?;
+        // Only count the record after it has been written completely.
+        self.file.seek(SeekFrom::Start(COUNT_OFFSET))?;
+        self.file.write_all(&(self.len as u64 + 1).to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Loads all embeddings stored in the cache into memory.
+    pub fn load(&mut self) -> Result<Embeddings, Error> {
+        self.load_io().map_err(Error::CacheIo)
+    }
+
+    /// Memory maps the embeddings stored in the cache, rather than reading them into memory. This
+    /// makes startup almost instant, even for large communities, and processes searching the same
+    /// community share the pages of the file.
+    ///
+    /// The cache is consumed, so no embeddings can be appended while they are mapped. Do not run
+    /// another process computing embeddings for the same cache at the same time.
+    pub fn into_mapped(self) -> Result<Embeddings, Error> {
+        // Safety: We own the only handle writing to the file and give it up here.
+        unsafe { Embeddings::map(&self.file, HEADER_SIZE as usize, self.len) }
+            .map_err(Error::CacheIo)
+    }
+
+    fn load_io(&mut self) -> Result<Embeddings, io::Error> {
+        self.file.seek(SeekFrom::Start(HEADER_SIZE))?;
+        let embeddings = Embeddings::from_reader_n(&mut BufReader::new(&mut self.file), self.len);
+        self.file.seek(SeekFrom::End(0))?;
+        embeddings
+    }
+}
+
+/// Reads the header from the start of `file` and checks whether its embeddings are compatible
+/// with `expected`. Returns the stored metadata and the number of embeddings in the file.
+fn read_header(
+    file: &mut File,
+    path: &Path,
+    expected: &CacheMetadata,
+) -> Result<(CacheMetadata, u64), Error> {
+    let mut header = [0u8; HEADER_SIZE as usize];
+    file.read_exact(&mut header).map_err(|error| {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            Error::incompatible_cache("File is too short to contain a header.")
+        } else {
+            Error::CacheIo(error)
+        }
+    })?;
+    let (stored, count) = CacheMetadata::from_header(&header)?;
+    if let Some(mismatch) = stored.mismatch(expected) {
+        let same_but_representations = CacheMetadata {
+            representation: expected.representation,
+            query_representation: expected.query_representation,
+            ..stored.clone()
+        };
+        if same_but_representations.mismatch(expected).is_none() {
+            return Err(Error::RepresentationChanged(mismatch));
+        }
+        return Err(Error::IncompatibleCache(format!(
+            "{mismatch} Delete '{}' to compute the embeddings anew.",
+            path.display()
+        )));
+    }
+    Ok((stored, count))
+}
+
+/// Path the migrated cache is written to, before it replaces the cache at `path`.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = OsString::from(path.as_os_str());
+    temporary.push(".tmp");
+    temporary.into()
+}
+
+/// Copies `count` records from `old` to `new`, keeping only those whose post id and text hash are
+/// `wanted`. `new` is positioned after a placeholder header. Returns the number of records kept.
+fn copy_wanted_records(
+    old: &mut impl Read,
+    new: &mut impl Write,
+    count: u64,
+    wanted: &HashSet<(u64, u64)>,
+) -> Result<u64, io::Error> {
+    new.write_all(&[0u8; HEADER_SIZE as usize])?;
+    let mut kept = HashSet::new();
+    let mut buf = [0u8; RECORD_SIZE];
+    for _ in 0..count {
+        old.read_exact(&mut buf)?;
+        let mut record = Record::default();
+        record.read_from_bytes(&buf);
+        let key = (record.id, record.text_hash);
+        // Also drop duplicates, so each post is embedded at most once.
+        if wanted.contains(&key) && kept.insert(key) {
+            new.write_all(&buf)?;
+        }
+    }
+    Ok(kept.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::embedding::{Embedding, EMBEDDING_SIZE, MODEL};
+
+    use super::*;
+
+    fn record(id: u64, text: &str) -> Record {
+        Record {
+            id,
+            text_hash: text_hash(text),
+            embedding: Embedding([id as f32; EMBEDDING_SIZE]),
+        }
+    }
+
+    fn metadata(documents: &[(u64, &str)]) -> CacheMetadata {
+        CacheMetadata {
+            model: MODEL.to_owned(),
+            representation: Representation::Symmetric,
+            query_representation: Representation::Symmetric,
+            dimension: EMBEDDING_SIZE as u32,
+            source_hash: source_hash(documents.iter().copied()),
+        }
+    }
+
+    #[test]
+    fn resume_after_reopening() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("posts.emb");
+        let documents = [(1, "a"), (2, "b")];
+        let metadata = metadata(&documents);
+
+        let mut cache = EmbeddingCache::open(&path, &metadata).unwrap();
+        cache.append(&record(1, "a")).unwrap();
+        drop(cache);
+        let mut cache = EmbeddingCache::open(&path, &metadata).unwrap();
+
+        assert_eq!(1, cache.len());
+        assert_eq!(vec![(2, "b")], cache.missing(&documents).unwrap());
+    }
+
+    #[test]
+    fn discard_incomplete_trailing_embedding() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("posts.emb");
+        let metadata = metadata(&[(1, "a"), (2, "b")]);
+        let mut cache = EmbeddingCache::open(&path, &metadata).unwrap();
+        cache.append(&record(1, "a")).unwrap();
+        drop(cache);
+        // Simulate a process dying in the middle of writing the second embedding
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0u8; 10]).unwrap();
+        drop(file);
+
+        let mut cache = EmbeddingCache::open(&path, &metadata).unwrap();
+        cache.append(&record(2, "b")).unwrap();
+
+        assert_eq!(2, cache.len());
+        assert_eq!(
+            HEADER_SIZE + 2 * RECORD_LEN,
+            fs::metadata(&path).unwrap().len()
+        );
+        assert_eq!(
+            Embeddings::from_vec(vec![record(1, "a"), record(2, "b")]),
+            cache.load().unwrap()
+        );
+    }
+
+    #[test]
+    fn memory_mapped_embeddings_equal_loaded_ones() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("posts.emb");
+        let metadata = metadata(&[(1, "a"), (2, "b")]);
+        let mut cache = EmbeddingCache::open(&path, &metadata).unwrap();
+        cache.append(&record(1, "a")).unwrap();
+        cache.append(&record(2, "b")).unwrap();
+
+        let loaded = cache.load().unwrap();
+        let mapped = cache.into_mapped().unwrap();
+
+        assert_eq!(loaded, mapped);
+        assert_eq!(
+            Embedding([2.; EMBEDDING_SIZE]).normalize().as_slice(),
+            mapped.vector(1)
+        );
+    }
+
+    #[test]
+    fn outdated_cache_of_different_posts() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("posts.emb");
+        EmbeddingCache::open(&path, &metadata(&[(1, "a"), (2, "b")])).unwrap();
+
+        let result = EmbeddingCache::open(&path, &metadata(&[(1, "a"), (2, "c")]));
+
+        assert!(matches!(result, Err(Error::CacheOutdated)));
+    }
+
+    #[test]
+    fn migrate_keeps_embeddings_of_unchanged_posts() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("posts.emb");
+        let old_documents = [(1, "a"), (2, "b"), (3, "c")];
+        let mut cache = EmbeddingCache::open(&path, &metadata(&old_documents)).unwrap();
+        for &(id, text) in &old_documents {
+            cache.append(&record(id, text)).unwrap();
+        }
+        drop(cache);
+
+        // Post 1 is deleted, post 2 is edited and post 4 is new
+        let new_documents = [(2, "b edited"), (3, "c"), (4, "d")];
+        let mut cache =
+            EmbeddingCache::migrate(&path, &metadata(&new_documents), &new_documents).unwrap();
+
+        assert_eq!(
+            Embeddings::from_vec(vec![record(3, "c")]),
+            cache.load().unwrap()
+        );
+        assert_eq!(
+            vec![(2, "b edited"), (4, "d")],
+            cache.missing(&new_documents).unwrap()
+        );
+        assert!(!temporary_path(&path).exists());
+    }
+
+    #[test]
+    fn refuse_cache_of_different_representation() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("posts.emb");
+        let mut metadata = metadata(&[(1, "a")]);
+        EmbeddingCache::open(&path, &metadata).unwrap();
+
+        metadata.representation = Representation::Document;
+        let result = EmbeddingCache::open(&path, &metadata);
+
+        assert!(matches!(result, Err(Error::RepresentationChanged(_))));
+    }
+
+    #[test]
+    fn recreate_cache_for_different_representation() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("posts.emb");
+        let documents = [(1, "a")];
+        let mut metadata = metadata(&documents);
+        let mut cache = EmbeddingCache::open(&path, &metadata).unwrap();
+        cache.append(&record(1, "a")).unwrap();
+        drop(cache);
+
+        metadata.representation = Representation::Document;
+        metadata.query_representation = Representation::Query;
+        let cache = EmbeddingCache::recreate(&path, &metadata).unwrap();
+        drop(cache);
+
+        let mut reopened = EmbeddingCache::open(&path, &metadata).unwrap();
+        assert_eq!(0, reopened.len());
+        assert_eq!(0, reopened.load().unwrap().len());
+    }
+
+    #[test]
+    fn refuse_cache_for_different_queries() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("posts.emb");
+        let mut metadata = metadata(&[(1, "a")]);
+        metadata.representation = Representation::Document;
+        metadata.query_representation = Representation::Query;
+        EmbeddingCache::open(&path, &metadata).unwrap();
+
+        metadata.query_representation = Representation::Symmetric;
+        let result = EmbeddingCache::open(&path, &metadata);
+
+        assert!(matches!(result, Err(Error::RepresentationChanged(_))));
+    }
+
+    #[test]
+    fn refuse_file_without_header() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("posts.emb");
+        // Raw embeddings, like caches have been written before they had a header.
+        let mut raw = Vec::new();
+        Embeddings::from_vec(vec![record(1, "a")])
+            .write(&mut raw)
+            .unwrap();
+        fs::write(&path, raw).unwrap();
+
+        let result = EmbeddingCache::open(&path, &metadata(&[(1, "a")]));
+
+        assert!(matches!(result, Err(Error::IncompatibleCache(_))));
+    }
+
+    #[test]
+    fn source_hash_depends_on_boundaries_between_texts() {
+        assert_ne!(
+            source_hash([(1, "ab"), (2, "c")]),
+            source_hash([(1, "a"), (2, "bc")])
+        )
+    }
+}
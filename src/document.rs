@@ -0,0 +1,330 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use crate::{Error, Post, PostReader};
+
+/// Which parts of a question are embedded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Content {
+    Title,
+    /// Title followed by the body of the question, without HTML markup.
+    TitleAndBody,
+    /// Title, body and the body of the accepted answer, if there is one.
+    TitleBodyAndAnswer,
+}
+
+/// Splits long texts into overlapping chunks of words, so each chunk fits into the context of the
+/// embedding model. The overlap keeps sentences at the border of two chunks findable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunking {
+    /// Maximum number of words in a chunk.
+    pub max_words: usize,
+    /// Number of words at the end of a chunk, which are repeated at the start of the next one.
+    /// Must be smaller than `max_words`.
+    pub overlap: usize,
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Self {
+            max_words: 200,
+            overlap: 40,
+        }
+    }
+}
+
+impl Chunking {
+    /// Keeps each text in a single chunk, no matter how long.
+    pub fn whole() -> Self {
+        Self {
+            max_words: usize::MAX,
+            overlap: 0,
+        }
+    }
+
+    /// Chunks of `text`, in order. Whitespace within a chunk is preserved. Empty if `text` does not
+    /// contain any words.
+    pub fn split<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        let words: Vec<_> = text
+            .split_whitespace()
+            .map(|word| {
+                let start = word.as_ptr() as usize - text.as_ptr() as usize;
+                (start, start + word.len())
+            })
+            .collect();
+        let max_words = self.max_words.max(1);
+        let step = max_words.saturating_sub(self.overlap).max(1);
+        let mut chunks = Vec::new();
+        let mut first = 0;
+        while first < words.len() {
+            let last = first.saturating_add(max_words).min(words.len()) - 1;
+            chunks.push(&text[words[first].0..words[last].1]);
+            if last == words.len() - 1 {
+                break;
+            }
+            first += step;
+        }
+        chunks
+    }
+}
+
+/// A question together with the texts embedded for it. Each chunk is embedded separately and
+/// maps back to the question by its id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    /// Post id of the question
+    pub id: u64,
+    pub title: String,
+    /// Distinct texts embedded for the question. Each one starts with the title, so chunks from
+    /// the middle of a long body keep their context.
+    pub chunks: Vec<String>,
+    /// Tags of the question. Empty, unless read from a `Posts.xml`.
+    pub tags: Vec<String>,
+    /// Upvotes minus downvotes
+    pub score: i64,
+    /// Whether the author of the question accepted an answer
+    pub answered: bool,
+    /// Time the question has been asked in ISO 8601, e.g. `2016-01-12T18:45:19.963`. Empty, unless
+    /// read from a `Posts.xml`.
+    pub creation_date: String,
+    pub view_count: u64,
+    pub answer_count: u64,
+    /// ISO 639-3 code of the language the question is written in, e.g. `deu` for German. Empty if
+    /// it can not be told reliably, or unless filled in by [`crate::Languages`].
+    pub language: String,
+}
+
+impl Document {
+    /// `body` is the plain text following the title, e.g. the body of the question.
+    pub fn new(id: u64, title: String, body: &str, chunking: Chunking) -> Self {
+        let mut chunks: Vec<String> = Vec::new();
+        for chunk in chunking.split(body) {
+            let chunk = format!("{title}\n\n{chunk}");
+            // Identical chunks would share an embedding anyway
+            if !chunks.contains(&chunk) {
+                chunks.push(chunk);
+            }
+        }
+        if chunks.is_empty() {
+            chunks.push(title.clone());
+        }
+        Self {
+            id,
+            title,
+            chunks,
+            tags: Vec::new(),
+            score: 0,
+            answered: false,
+            creation_date: String::new(),
+            view_count: 0,
+            answer_count: 0,
+            language: String::new(),
+        }
+    }
+}
+
+/// Builds a document for each question in a `Posts.xml`, in the order of the file. Reads the file
+/// twice, if accepted answers are part of the `content`, so only the accepted answers are kept in
+/// memory.
+pub fn read_documents(
+    posts_xml: &Path,
+    content: Content,
+    chunking: Chunking,
+) -> Result<Vec<Document>, Error> {
+    // Tuples of id, title, body, accepted answer id and a document holding the metadata only
+    let mut questions = Vec::new();
+    let mut reader = PostReader::new(posts_xml)?;
+    while let Some(post) = reader.next_post()? {
+        if let Post::Question {
+            id,
+            title,
+            body,
+            accepted_answer_id,
+            tags,
+            score,
+            creation_date,
+            view_count,
+            answer_count,
+        } = post
+        {
+            let metadata = Document {
+                tags,
+                score,
+                answered: accepted_answer_id.is_some(),
+                creation_date,
+                view_count,
+                answer_count,
+                ..Document::new(id, String::new(), "", Chunking::whole())
+            };
+            questions.push((id, title, body, accepted_answer_id, metadata));
+        }
+    }
+
+    let mut accepted_answers = HashMap::new();
+    if content == Content::TitleBodyAndAnswer {
+        let wanted: HashSet<u64> = questions
+            .iter()
+            .filter_map(|(_, _, _, accepted, _)| *accepted)
+            .collect();
+        let mut reader = PostReader::new(posts_xml)?;
+        while let Some(post) = reader.next_post()? {
+            if let Post::Answer { id, body, .. } = post {
+                if wanted.contains(&id) {
+                    accepted_answers.insert(id, body);
+                }
+            }
+        }
+    }
+
+    let documents = questions
+        .into_iter()
+        .map(|(id, title, body, accepted_answer_id, metadata)| {
+            let text = match content {
+                Content::Title => String::new(),
+                Content::TitleAndBody => strip_html(&body),
+                Content::TitleBodyAndAnswer => {
+                    let mut text = strip_html(&body);
+                    if let Some(answer) =
+                        accepted_answer_id.and_then(|id| accepted_answers.get(&id))
+                    {
+                        text.push_str("\n\n");
+                        text.push_str(&strip_html(answer));
+                    }
+                    text
+                }
+            };
+            let Document { title, chunks, .. } = Document::new(id, title, &text, chunking);
+            Document {
+                title,
+                chunks,
+                ..metadata
+            }
+        })
+        .collect();
+    Ok(documents)
+}
+
+/// Plain text of a post body. Removes tags, decodes character references and collapses runs of
+/// whitespace into a single space.
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '<' => {
+                // Tags separate words, e.g. `<p>a</p><p>b</p>`.
+                text.push(' ');
+                rest = rest.find('>').map(|end| &rest[end + 1..]).unwrap_or("");
+            }
+            '&' => match decode_reference(rest) {
+                Some((decoded, len)) => {
+                    text.push(decoded);
+                    rest = &rest[len..];
+                }
+                None => {
+                    text.push('&');
+                    rest = &rest[1..];
+                }
+            },
+            _ => {
+                text.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Decodes the character reference at the start of `text`, e.g. `&amp;` or `&#39;`. Returns the
+/// character and the length of the reference in bytes.
+fn decode_reference(text: &str) -> Option<(char, usize)> {
+    let end = text.find(';')?;
+    let name = &text[1..end];
+    let decoded = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        _ => {
+            let code = if let Some(hex) = name.strip_prefix("#x").or(name.strip_prefix("#X")) {
+                u32::from_str_radix(hex, 16).ok()?
+            } else {
+                name.strip_prefix('#')?.parse().ok()?
+            };
+            char::from_u32(code)?
+        }
+    };
+    Some((decoded, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_documents, strip_html, Chunking, Content, Document};
+
+    #[test]
+    fn strip_tags_and_references() {
+        let html = "<p>Use <code>a &lt; b</code> &amp; don&#39;t</p>\n\n<p>panic&#xA;</p>";
+
+        assert_eq!("Use a < b & don't panic", strip_html(html));
+    }
+
+    #[test]
+    fn keep_unknown_references() {
+        assert_eq!("AT&T &foo; &", strip_html("AT&T &foo; &"));
+    }
+
+    #[test]
+    fn overlapping_chunks() {
+        let chunking = Chunking {
+            max_words: 4,
+            overlap: 1,
+        };
+
+        let chunks = chunking.split("a b  c d e f g h i");
+
+        assert_eq!(vec!["a b  c d", "d e f g", "g h i"], chunks);
+    }
+
+    #[test]
+    fn short_text_is_a_single_chunk() {
+        let chunks = Chunking::default().split(" short text ");
+
+        assert_eq!(vec!["short text"], chunks);
+    }
+
+    #[test]
+    fn document_without_body_embeds_title() {
+        let document = Document::new(1, "Title".to_owned(), "", Chunking::default());
+
+        assert_eq!(vec!["Title"], document.chunks);
+    }
+
+    #[test]
+    fn include_accepted_answer() {
+        let documents = read_documents(
+            "./tests/small-posts.xml".as_ref(),
+            Content::TitleBodyAndAnswer,
+            Chunking::default(),
+        )
+        .unwrap();
+
+        let health = documents.iter().find(|document| document.id == 2).unwrap();
+        assert_eq!("Is 3D printing safe for your health?", health.title);
+        assert_eq!(vec!["print-material", "safety", "health"], health.tags);
+        assert_eq!(32, health.score);
+        assert!(health.answered);
+        assert_eq!("2016-01-12T18:45:51.287", health.creation_date);
+        assert_eq!(6381, health.view_count);
+        assert_eq!(4, health.answer_count);
+        assert!(health.chunks[0].starts_with("Is 3D printing safe for your health?\n\nI would"));
+        let text = health.chunks.concat();
+        // Accepted answer
+        assert!(text.contains("There is very little information about safety available"));
+        // Other answer
+        assert!(!text.contains("Almost all 3D printers have issues"));
+    }
+}
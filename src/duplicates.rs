@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use crate::{Embeddings, Error, VectorIndex};
+
+/// Pairs of distinct posts with embeddings at least `threshold` similar, as tuples of the lower
+/// post id, the higher post id and their similarity. Most similar first, ties ordered by id. Posts
+/// with several embeddings are as similar as their most similar embeddings.
+///
+/// Each embedding is joined with its `neighbours` most similar embeddings found by `index`, rather
+/// than with every other one, so an approximate index makes this fast even for large communities.
+/// Pairs beyond the nearest `neighbours` may be missed. Degenerate embeddings are skipped.
+pub fn duplicate_pairs(
+    index: &(dyn VectorIndex + Sync),
+    embeddings: &Embeddings,
+    threshold: f32,
+    neighbours: usize,
+) -> Result<Vec<(u64, u64, f32)>, Error> {
+    let records = embeddings.records();
+    let matches = (0..records.len())
+        .into_par_iter()
+        .filter(|&needle| !records[needle].embedding.is_degenerate())
+        .map(|needle| {
+            // The needle finds itself, too.
+            let similar = index.search(embeddings, &records[needle].embedding, neighbours + 1)?;
+            let id = records[needle].id;
+            let pairs: Vec<_> = similar
+                .into_iter()
+                .filter(|&(other, similarity)| similarity >= threshold && records[other].id != id)
+                .map(|(other, similarity)| {
+                    let other = records[other].id;
+                    (id.min(other), id.max(other), similarity)
+                })
+                .collect();
+            Ok(pairs)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let mut pairs: HashMap<(u64, u64), f32> = HashMap::new();
+    for (a, b, similarity) in matches.into_iter().flatten() {
+        let best = pairs.entry((a, b)).or_insert(similarity);
+        *best = best.max(similarity);
+    }
+    let mut pairs: Vec<_> = pairs
+        .into_iter()
+        .map(|((a, b), similarity)| (a, b, similarity))
+        .collect();
+    pairs.sort_by(|(a1, b1, s1), (a2, b2, s2)| s2.total_cmp(s1).then((a1, b1).cmp(&(a2, b2))));
+    Ok(pairs)
+}
+
+/// Groups posts into clusters of duplicates, i.e. the connected components of the graph with an
+/// edge for each pair. Post ids within a cluster are ascending. Larger clusters come first, ties
+/// are ordered by their lowest post id.
+pub fn duplicate_clusters(pairs: &[(u64, u64, f32)]) -> Vec<Vec<u64>> {
+    let mut sets = UnionFind::default();
+    for &(a, b, _) in pairs {
+        sets.union(a, b);
+    }
+    let mut clusters: HashMap<u64, Vec<u64>> = HashMap::new();
+    let ids: Vec<u64> = sets.parent.keys().copied().collect();
+    for id in ids {
+        let root = sets.find(id);
+        clusters.entry(root).or_default().push(id);
+    }
+    let mut clusters: Vec<Vec<u64>> = clusters.into_values().collect();
+    for cluster in &mut clusters {
+        cluster.sort_unstable();
+    }
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+    clusters
+}
+
+/// Disjoint sets of post ids. Unions attach the smaller set to the larger one and lookups halve
+/// the paths they walk, so trees stay flat even for large clusters.
+#[derive(Default)]
+struct UnionFind {
+    parent: HashMap<u64, u64>,
+    /// Number of ids in the set of each root
+    size: HashMap<u64, usize>,
+}
+
+impl UnionFind {
+    fn find(&mut self, mut id: u64) -> u64 {
+        let mut parent = *self.parent.entry(id).or_insert(id);
+        while parent != id {
+            // Point to the grandparent, halving the path for the next lookup
+            let grandparent = self.parent[&parent];
+            self.parent.insert(id, grandparent);
+            id = parent;
+            parent = grandparent;
+        }
+        id
+    }
+
+    fn union(&mut self, a: u64, b: u64) {
+        let a = self.find(a);
+        let b = self.find(b);
+        if a == b {
+            return;
+        }
+        let size_a = self.size.get(&a).copied().unwrap_or(1);
+        let size_b = self.size.get(&b).copied().unwrap_or(1);
+        let (small, large) = if size_a < size_b { (a, b) } else { (b, a) };
+        self.parent.insert(small, large);
+        self.size.insert(large, size_a + size_b);
+        self.size.remove(&small);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{duplicate_clusters, duplicate_pairs};
+    use crate::{test_util::record, Embeddings, ExactSearch};
+
+    #[test]
+    fn pairs_above_threshold() {
+        let embeddings = Embeddings::from_vec(vec![
+            record(1, &[1., 0., 0.]),
+            record(2, &[1., 0.01, 0.]),
+            record(3, &[0., 1., 0.]),
+            // Second chunk of post 3
+            record(3, &[0., 0., 1.]),
+            record(4, &[0., 0.02, 1.]),
+        ]);
+
+        let pairs = duplicate_pairs(&ExactSearch, &embeddings, 0.99, 3).unwrap();
+
+        let ids: Vec<_> = pairs.iter().map(|&(a, b, _)| (a, b)).collect();
+        assert_eq!(vec![(1, 2), (3, 4)], ids);
+    }
+
+    #[test]
+    fn transitive_pairs_form_one_cluster() {
+        let pairs = [(5, 7, 0.99), (1, 2, 0.98), (2, 9, 0.97), (3, 9, 0.96)];
+
+        let clusters = duplicate_clusters(&pairs);
+
+        assert_eq!(vec![vec![1, 2, 3, 9], vec![5, 7]], clusters);
+    }
+
+    #[test]
+    fn long_chain_of_duplicates() {
+        let pairs: Vec<_> = (1..200_000).rev().map(|id| (id, id + 1, 0.99)).collect();
+
+        let clusters = duplicate_clusters(&pairs);
+
+        assert_eq!(1, clusters.len());
+        assert_eq!(200_000, clusters[0].len());
+    }
+}
@@ -1,20 +1,60 @@
 use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
     convert::TryInto,
+    fs::File,
     io::{self, BufRead, Write},
-    mem::size_of,
+    mem::{align_of, size_of},
 };
 
 use crate::Error;
 use aleph_alpha_client::{
     cosine_similarity, Client, Prompt, SemanticRepresentation, TaskSemanticEmbedding,
 };
-use ordered_float::NotNan;
+use bytemuck::{Pod, Zeroable};
+use memmap2::{Mmap, MmapOptions};
+use ordered_float::OrderedFloat;
+use rayon::prelude::*;
 
 pub const EMBEDDING_SIZE: usize = 128;
 
+/// Model the Aleph Alpha client uses to compute semantic embeddings.
+pub const MODEL: &str = "luminous-base";
+
+/// How a text is represented by the embedding model. Symmetric embeddings are compared with other
+/// symmetric embeddings. Queries are compared with documents. Short queries against longer stored
+/// texts usually match better with `Query` and `Document` representations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    Symmetric,
+    Document,
+    Query,
+}
+
+impl Representation {
+    /// Representation of queries which are compared with embeddings of this representation.
+    pub fn query(self) -> Representation {
+        match self {
+            Representation::Symmetric => Representation::Symmetric,
+            Representation::Document | Representation::Query => Representation::Query,
+        }
+    }
+}
+
+impl From<Representation> for SemanticRepresentation {
+    fn from(source: Representation) -> Self {
+        match source {
+            Representation::Symmetric => SemanticRepresentation::Symmetric,
+            Representation::Document => SemanticRepresentation::Document,
+            Representation::Query => SemanticRepresentation::Query,
+        }
+    }
+}
+
 /// Embeddings encode meaning. They are high dimensional vectors those angles are used to determine
 /// similarity of different prompts.
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Pod, Zeroable)]
+#[repr(transparent)]
 pub struct Embedding(pub [f32; EMBEDDING_SIZE]);
 
 impl Embedding {
@@ -30,10 +70,60 @@ impl Embedding {
         Ok(Self(array))
     }
 
+    /// Embeds a single text using the Aleph Alpha API. Requests are repeated until they succeed,
+    /// if the API is busy or we send too many requests.
+    pub async fn from_text(
+        client: &Client,
+        text: &str,
+        representation: Representation,
+    ) -> Result<Self, Error> {
+        let embedding =
+            semantic_embedding(client, text, representation, Some(EMBEDDING_SIZE as u32)).await?;
+        Self::try_from_slice(&embedding)
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.0
+    }
+
+    /// Cosine similarity of the two embeddings. `NaN` if either of them is degenerate.
     pub fn similarity(&self, other: &Embedding) -> f32 {
         cosine_similarity(&self.0, &other.0)
     }
 
+    /// Dot product of the two embeddings. Equals the cosine similarity, if both are normalized.
+    ///
+    /// Summing into independent lanes allows the compiler to vectorize the loop using SIMD
+    /// instructions.
+    pub fn dot(&self, other: &Embedding) -> f32 {
+        const LANES: usize = 8;
+        let mut sums = [0f32; LANES];
+        for (a, b) in self.0.chunks_exact(LANES).zip(other.0.chunks_exact(LANES)) {
+            for lane in 0..LANES {
+                sums[lane] += a[lane] * b[lane];
+            }
+        }
+        sums.iter().sum()
+    }
+
+    /// An embedding is degenerate if it has no direction, i.e. it is all zeroes or contains values
+    /// which are not finite. Its similarity to other embeddings is undefined.
+    pub fn is_degenerate(&self) -> bool {
+        let norm = self.dot(self);
+        !norm.is_finite() || norm == 0.
+    }
+
+    /// Scales the embedding to unit length, so its cosine similarity to other normalized
+    /// embeddings is just the dot product. Degenerate embeddings become all `NaN`, so their
+    /// similarity to anything is `NaN`, too.
+    pub fn normalize(&self) -> Self {
+        if self.is_degenerate() {
+            return Self([f32::NAN; EMBEDDING_SIZE]);
+        }
+        let norm = self.dot(self).sqrt();
+        Self(self.0.map(|value| value / norm))
+    }
+
     /// Write the embedding into a binary buffer.
     pub fn write_to_bytes(&self, buf: &mut [u8; EMBEDDING_SIZE * size_of::<f32>()]) {
         for (bytes, float) in buf.chunks_exact_mut(size_of::<f32>()).zip(self.0) {
@@ -55,18 +145,145 @@ impl Default for Embedding {
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Number of embeddings scanned by a single thread at once, while searching. Fewer embeddings are
+/// scanned on the calling thread alone.
+const PARALLEL_SCAN_CHUNK: usize = 16 * 1024;
+
+/// Size of a [`Record`] in binary form.
+pub const RECORD_SIZE: usize = 2 * size_of::<u64>() + EMBEDDING_SIZE * size_of::<f32>();
+
+/// An embedding together with the post it has been computed for. The memory layout equals the
+/// binary form on little endian platforms, so records can be used directly from a memory mapped
+/// file.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Pod, Zeroable)]
+#[repr(C)]
+pub struct Record {
+    /// Id of the post the embedding has been computed for.
+    pub id: u64,
+    /// Hash of the embedded text, see [`text_hash`]. Tells us whether the embedding is still up to
+    /// date, if the post is edited.
+    pub text_hash: u64,
+    pub embedding: Embedding,
+}
+
+impl Record {
+    /// Write the record into a binary buffer.
+    pub fn write_to_bytes(&self, buf: &mut [u8; RECORD_SIZE]) {
+        let (id, rest) = buf.split_at_mut(size_of::<u64>());
+        let (text_hash, embedding) = rest.split_at_mut(size_of::<u64>());
+        id.copy_from_slice(&self.id.to_le_bytes());
+        text_hash.copy_from_slice(&self.text_hash.to_le_bytes());
+        self.embedding.write_to_bytes(embedding.try_into().unwrap());
+    }
+
+    /// Load record from a binary buffer.
+    pub fn read_from_bytes(&mut self, buf: &[u8; RECORD_SIZE]) {
+        let (id, rest) = buf.split_at(size_of::<u64>());
+        let (text_hash, embedding) = rest.split_at(size_of::<u64>());
+        self.id = u64::from_le_bytes(id.try_into().unwrap());
+        self.text_hash = u64::from_le_bytes(text_hash.try_into().unwrap());
+        self.embedding
+            .read_from_bytes(embedding.try_into().unwrap());
+    }
+}
+
+/// Embeds `text` using the Aleph Alpha API, compressed to `compress_to_size` dimensions, or with
+/// all dimensions of the model if `None`. Requests are repeated until they succeed, if the API is
+/// busy or we send too many requests.
+pub(crate) async fn semantic_embedding(
+    client: &Client,
+    text: &str,
+    representation: Representation,
+    compress_to_size: Option<u32>,
+) -> Result<Vec<f32>, Error> {
+    let task = TaskSemanticEmbedding {
+        prompt: Prompt::from_text(text),
+        representation: representation.into(),
+        compress_to_size,
+    };
+    loop {
+        match client.semantic_embedding(&task, &Default::default()).await {
+            Ok(output) => break Ok(output.embedding),
+            Err(error) => match error {
+                aleph_alpha_client::Error::TooManyRequests | aleph_alpha_client::Error::Busy => (),
+                _ => break Err(Error::Embedding(error.to_string())),
+            },
+        }
+    }
+}
+
+/// Hash of a single embedded text. Uses FNV-1a, so the hash stays stable across platforms and
+/// compiler versions, and can be persisted.
+pub fn text_hash(text: &str) -> u64 {
+    fnv1a(FNV_OFFSET_BASIS, text.as_bytes())
+}
+
+pub(crate) const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// Continues an FNV-1a hash with `bytes`.
+pub(crate) fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    const PRIME: u64 = 0x100000001b3;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(PRIME);
+    }
+    hash
+}
+
+/// Embeddings of many posts. All embeddings are normalized (see [`Embedding::normalize`]), so
+/// comparing them with a needle only takes a dot product.
+#[derive(Debug)]
 pub struct Embeddings {
-    /// Store all 128 sized embeddings in contigious memory
-    embeddings: Vec<Embedding>,
+    /// Store all embeddings together with the ids of their posts in contigious memory
+    storage: Storage,
 }
 
-impl Embeddings {
+#[derive(Debug)]
+enum Storage {
+    /// Records in memory owned by this process.
+    Owned(Vec<Record>),
+    /// Records in a read only memory mapped file. Processes mapping the same file share its pages.
+    Mapped {
+        map: Mmap,
+        /// Position of the first record within the file
+        offset: usize,
+        /// Number of records
+        len: usize,
+    },
+}
 
+impl Embeddings {
     pub fn new() -> Self {
-        Self {
-            embeddings: Vec::new(),
+        Self::from_vec(Vec::new())
+    }
+
+    /// Memory maps `len` records starting at `offset` in `file`, rather than reading them into
+    /// memory. Startup is almost instant, since pages are only read from disk when they are
+    /// accessed. The embeddings in the file must already be normalized.
+    ///
+    /// # Safety
+    ///
+    /// The mapped part of the file must not be modified while the embeddings are alive, since the
+    /// changes would become visible to the embeddings.
+    pub unsafe fn map(file: &File, offset: usize, len: usize) -> Result<Self, io::Error> {
+        if cfg!(target_endian = "big") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Memory mapping embeddings requires a little endian platform.",
+            ));
         }
+        if !offset.is_multiple_of(align_of::<Record>()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Records in memory mapped files must be aligned.",
+            ));
+        }
+        let map = MmapOptions::new()
+            .len(offset + len * RECORD_SIZE)
+            .map(file)?;
+        Ok(Self {
+            storage: Storage::Mapped { map, offset, len },
+        })
     }
 
     pub fn from_reader_n(read: &mut impl BufRead, n: usize) -> Result<Self, io::Error> {
@@ -75,55 +292,149 @@ impl Embeddings {
         Ok(embeddings)
     }
 
-    pub fn from_vec(embeddings: Vec<Embedding>) -> Self {
-        Self { embeddings }
+    /// Normalizes the embeddings of `records` and takes ownership of them.
+    pub fn from_vec(mut records: Vec<Record>) -> Self {
+        for record in &mut records {
+            record.embedding = record.embedding.normalize();
+        }
+        Self {
+            storage: Storage::Owned(records),
+        }
     }
 
+    /// Embeds the texts of several posts, each given as a tuple of post id and text.
     pub async fn from_texts(
         client: &Client,
-        facts: impl IntoIterator<Item = &'_ str>,
+        facts: impl IntoIterator<Item = (u64, &'_ str)>,
+        representation: Representation,
     ) -> Result<Self, Error> {
-        let mut embeddings = Vec::new();
-        for fact in facts {
-            let task = TaskSemanticEmbedding {
-                prompt: Prompt::from_text(fact),
-                representation: SemanticRepresentation::Symmetric,
-                compress_to_size: Some(EMBEDDING_SIZE as u32),
-            };
-            let mut embedding = None;
-            while embedding.is_none() {
-                embedding = match client
-                    .semantic_embedding(&task, &Default::default())
-                    .await
-                {
-                    Ok(output) => Some(output.embedding),
-                    Err(error) => match error {
-                        aleph_alpha_client::Error::TooManyRequests
-                        | aleph_alpha_client::Error::Busy => None,
-                        _ => return Err(Error::Embedding(error.to_string())),
-                    },
-                };
+        let mut records = Vec::new();
+        for (id, fact) in facts {
+            records.push(Record {
+                id,
+                text_hash: text_hash(fact),
+                embedding: Embedding::from_text(client, fact, representation).await?,
+            })
+        }
+        Ok(Self::from_vec(records))
+    }
+
+    /// Number of embeddings
+    pub fn len(&self) -> usize {
+        self.records().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records().is_empty()
+    }
+
+    /// Appends a record with its embedding normalized. Memory mapped embeddings are copied into
+    /// memory first.
+    pub fn push(&mut self, mut record: Record) {
+        record.embedding = record.embedding.normalize();
+        self.records_mut().push(record)
+    }
+
+    pub fn records(&self) -> &[Record] {
+        match &self.storage {
+            Storage::Owned(records) => records,
+            Storage::Mapped { map, offset, len } => {
+                bytemuck::cast_slice(&map[*offset..*offset + *len * RECORD_SIZE])
             }
-            embeddings.push(Embedding::try_from_slice(&embedding.unwrap())?)
         }
-        Ok(Self::from_vec(embeddings))
     }
 
-    pub fn find_most_similar(&self, needle: &Embedding) -> usize {
-        let (pos_answer, _similarity) = self
-            .embeddings
-            .iter()
-            .map(|embedding| NotNan::new(embedding.similarity(needle)).unwrap())
-            .enumerate()
-            .max_by_key(|(_index, similarity)| *similarity)
-            .unwrap();
-        pos_answer
+    fn records_mut(&mut self) -> &mut Vec<Record> {
+        if let Storage::Mapped { .. } = self.storage {
+            self.storage = Storage::Owned(self.records().to_vec());
+        }
+        match &mut self.storage {
+            Storage::Owned(records) => records,
+            Storage::Mapped { .. } => unreachable!(),
+        }
+    }
+
+    /// Identifies the posts and texts these embeddings have been computed for, and the vectors
+    /// themselves. Indices built for these embeddings remember the fingerprint, so they can tell if
+    /// they are outdated, even if the same texts have been embedded anew, e.g. with a different
+    /// representation.
+    pub fn fingerprint(&self) -> u64 {
+        fnv1a(FNV_OFFSET_BASIS, bytemuck::cast_slice(self.records()))
+    }
+
+    /// Vector of the embedding at `index`. Borrowed directly from the file, if memory mapped.
+    pub fn vector(&self, index: usize) -> &[f32] {
+        self.records()[index].embedding.as_slice()
+    }
+
+    /// Id of the post the embedding at `index` has been computed for.
+    pub fn id(&self, index: usize) -> u64 {
+        self.records()[index].id
+    }
+
+    /// Position of the embedding computed for the post with the given id.
+    pub fn position(&self, id: u64) -> Option<usize> {
+        self.records().iter().position(|record| record.id == id)
+    }
+
+    /// Embedding computed for the post with the given id.
+    pub fn get(&self, id: u64) -> Option<&Embedding> {
+        self.position(id)
+            .map(|index| &self.records()[index].embedding)
+    }
+
+    /// Index of the embedding most similar to `needle`. Use [`Self::id`] to learn which post it
+    /// belongs to. `None` if there are no embeddings to compare with. Degenerate embeddings (see
+    /// [`Embedding::is_degenerate`]) are never similar to anything and therefore skipped. Ties are
+    /// broken like in [`Self::find_top_k`].
+    pub fn find_most_similar(&self, needle: &Embedding) -> Result<Option<usize>, Error> {
+        let best = self.find_top_k(needle, 1)?.first().map(|&(index, _)| index);
+        Ok(best)
+    }
+
+    /// The `k` embeddings most similar to `needle` as tuples of index and similarity. Most similar
+    /// first. Ties are broken in favour of the lower post id, then the lower index, so the order
+    /// does not depend on the order of the records. Degenerate embeddings are skipped. Fails if
+    /// `needle` is degenerate.
+    ///
+    /// Large collections are scanned in parallel.
+    pub fn find_top_k(&self, needle: &Embedding, k: usize) -> Result<Vec<(usize, f32)>, Error> {
+        if needle.is_degenerate() {
+            return Err(Error::DegenerateEmbedding);
+        }
+        let needle = needle.normalize();
+        // Degenerate embeddings are stored as NaN, so they drop out here.
+        let scan = |offset: usize, records: &[Record]| {
+            let scored = records
+                .iter()
+                .enumerate()
+                .map(|(index, record)| ((record.id, offset + index), record.embedding.dot(&needle)))
+                .filter(|(_index, similarity)| !similarity.is_nan());
+            top_k(scored, k)
+        };
+        let records = self.records();
+        let best = if records.len() < PARALLEL_SCAN_CHUNK {
+            scan(0, records)
+        } else {
+            let best_per_chunk: Vec<_> = records
+                .par_chunks(PARALLEL_SCAN_CHUNK)
+                .enumerate()
+                .flat_map_iter(|(chunk, records)| scan(chunk * PARALLEL_SCAN_CHUNK, records))
+                .collect();
+            // Ties are broken by id and index, so the result does not depend on how the work has
+            // been split.
+            top_k(best_per_chunk.into_iter(), k)
+        };
+        Ok(best
+            .into_iter()
+            .map(|((_id, index), similarity)| (index, similarity))
+            .collect())
     }
 
     pub fn write(&self, write: &mut impl Write) -> Result<(), io::Error> {
-        let mut buf = [0u8; EMBEDDING_SIZE * size_of::<f32>()];
-        for embedding in &self.embeddings {
-            embedding.write_to_bytes(&mut buf);
+        let mut buf = [0u8; RECORD_SIZE];
+        for record in self.records() {
+            record.write_to_bytes(&mut buf);
             write.write_all(&buf)?;
         }
         write.flush()?;
@@ -132,18 +443,43 @@ impl Embeddings {
 
     /// Read n embeddings from reader
     pub fn read_n(&mut self, read: &mut impl BufRead, n: usize) -> Result<(), io::Error> {
-        self.embeddings.clear();
-        let mut buf = [0u8; EMBEDDING_SIZE * size_of::<f32>()];
+        let mut records = Vec::with_capacity(n);
+        let mut buf = [0u8; RECORD_SIZE];
         for _ in 0..n {
             read.read_exact(&mut buf)?;
-            let mut embedding = Embedding::new();
-            embedding.read_from_bytes(&buf);
-            self.embeddings.push(embedding)
+            let mut record = Record::default();
+            record.read_from_bytes(&buf);
+            records.push(record)
         }
+        self.storage = Storage::Owned(records);
         Ok(())
     }
 }
 
+/// Picks the `k` highest scores from tuples of key and score, highest first. Keys are e.g. indices
+/// or post ids. Ties are broken in favour of the lower key. Only `k` elements are kept in memory
+/// at any time.
+pub(crate) fn top_k<K: Ord>(scored: impl Iterator<Item = (K, f32)>, k: usize) -> Vec<(K, f32)> {
+    // Min-heap of the best `k` seen so far, so the worst of them is at the top.
+    let mut heap = BinaryHeap::with_capacity(k + 1);
+    for (index, score) in scored {
+        heap.push(Reverse((OrderedFloat(score), Reverse(index))));
+        if heap.len() > k {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse((OrderedFloat(score), Reverse(index)))| (index, score))
+        .collect()
+}
+
+impl PartialEq for Embeddings {
+    fn eq(&self, other: &Self) -> bool {
+        self.records() == other.records()
+    }
+}
+
 impl Default for Embeddings {
     fn default() -> Self {
         Self::new()
@@ -154,6 +490,10 @@ impl Default for Embeddings {
 mod tests {
     use std::io::Cursor;
 
+    use proptest::prelude::*;
+
+    use crate::{BinaryEmbeddings, Int8Embeddings};
+
     use super::*;
 
     #[test]
@@ -172,12 +512,180 @@ mod tests {
     fn multiple_embedding_to_and_fro_bytes() {
         let embedding =
             Embedding::try_from_slice(&(0..128).map(|i| i as f32).collect::<Vec<_>>()).unwrap();
+        let record = Record {
+            id: 42,
+            text_hash: text_hash("Forty-two"),
+            embedding,
+        };
 
-        let embeddings = Embeddings::from_vec(vec![embedding, embedding]);
+        let embeddings = Embeddings::from_vec(vec![record, record]);
         let mut buf = Vec::new();
         embeddings.write(&mut buf).unwrap();
         let loaded = Embeddings::from_reader_n(&mut Cursor::new(buf), 2).unwrap();
 
         assert_eq!(embeddings, loaded)
     }
+
+    #[test]
+    fn dot_product_of_normalized_embeddings_is_cosine_similarity() {
+        let a = Embedding(std::array::from_fn(|i| i as f32));
+        let b = Embedding(std::array::from_fn(|i| (i % 7) as f32 - 3.));
+
+        let dot = a.normalize().dot(&b.normalize());
+
+        assert!((a.similarity(&b) - dot).abs() < 1e-6);
+    }
+
+    #[test]
+    fn parallel_scan_equals_sequential_scan() {
+        let records: Vec<_> = (0..3 * PARALLEL_SCAN_CHUNK + 17)
+            .map(|id| Record {
+                id: id as u64,
+                text_hash: 0,
+                // Many repeated embeddings, so ties need to be broken consistently.
+                embedding: Embedding(std::array::from_fn(|i| ((id * 31 + i) % 97) as f32 - 48.)),
+            })
+            .collect();
+        let embeddings = Embeddings::from_vec(records);
+        let needle = Embedding(std::array::from_fn(|i| (i % 5) as f32 - 2.));
+
+        let parallel = embeddings.find_top_k(&needle, 10).unwrap();
+        let normalized = needle.normalize();
+        let sequential = top_k(
+            embeddings
+                .records()
+                .iter()
+                .map(|record| record.embedding.dot(&normalized))
+                .enumerate(),
+            10,
+        );
+
+        assert_eq!(sequential, parallel);
+    }
+
+    #[test]
+    fn no_most_similar_in_empty_embeddings() {
+        let embeddings = Embeddings::new();
+
+        let best = embeddings.find_most_similar(&Embedding([1.; EMBEDDING_SIZE]));
+
+        assert_eq!(None, best.unwrap());
+    }
+
+    #[test]
+    fn degenerate_needle_is_an_error() {
+        let embeddings = Embeddings::from_vec(vec![Record {
+            embedding: Embedding([1.; EMBEDDING_SIZE]),
+            ..Record::default()
+        }]);
+
+        let result = embeddings.find_most_similar(&Embedding::new());
+
+        assert!(matches!(result, Err(Error::DegenerateEmbedding)));
+    }
+
+    #[test]
+    fn top_k_sorted_by_score() {
+        let scored = [(0, 0.5), (1, 0.9), (2, -0.3), (3, 0.7), (4, 0.9)];
+
+        let top = top_k(scored.into_iter(), 3);
+
+        assert_eq!(vec![(1, 0.9), (4, 0.9), (3, 0.7)], top);
+    }
+
+    #[test]
+    fn top_k_with_fewer_candidates_than_k() {
+        let top = top_k([(0, 0.5), (1, 0.9)].into_iter(), 5);
+
+        assert_eq!(vec![(1, 0.9), (0, 0.5)], top);
+    }
+
+    /// Values including those which make embeddings degenerate.
+    fn any_value() -> impl Strategy<Value = f32> {
+        prop_oneof![
+            8 => -1f32..1.,
+            1 => Just(0f32),
+            1 => Just(f32::NAN),
+            1 => Just(f32::INFINITY),
+            1 => Just(f32::MAX),
+        ]
+    }
+
+    /// Embeddings with arbitrary values, often all zeroes.
+    fn any_embedding() -> impl Strategy<Value = Embedding> {
+        prop_oneof![
+            4 => prop::collection::vec(any_value(), EMBEDDING_SIZE)
+                .prop_map(|values| Embedding::try_from_slice(&values).unwrap()),
+            1 => Just(Embedding::new()),
+        ]
+    }
+
+    fn any_embeddings() -> impl Strategy<Value = Embeddings> {
+        prop::collection::vec(any_embedding(), 0..20).prop_map(|embeddings| {
+            Embeddings::from_vec(
+                embeddings
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, embedding)| Record {
+                        id: id as u64,
+                        text_hash: 0,
+                        embedding,
+                    })
+                    .collect(),
+            )
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn top_k_never_panics_and_is_well_formed(
+            embeddings in any_embeddings(),
+            needle in any_embedding(),
+            k in 0usize..25,
+        ) {
+            match embeddings.find_top_k(&needle, k) {
+                Err(Error::DegenerateEmbedding) => prop_assert!(needle.is_degenerate()),
+                Err(error) => prop_assert!(false, "Unexpected error: {error}"),
+                Ok(top) => {
+                    prop_assert!(!needle.is_degenerate());
+                    prop_assert!(top.len() <= k.min(embeddings.len()));
+                    for &(index, similarity) in &top {
+                        prop_assert!(!similarity.is_nan());
+                        prop_assert!(!embeddings.records()[index].embedding.is_degenerate());
+                    }
+                    for pair in top.windows(2) {
+                        prop_assert!(pair[0].1 >= pair[1].1);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn most_similar_is_first_of_top_k(
+            embeddings in any_embeddings(),
+            needle in any_embedding(),
+        ) {
+            match embeddings.find_most_similar(&needle) {
+                Ok(best) => {
+                    let top = embeddings.find_top_k(&needle, 3).unwrap();
+                    prop_assert_eq!(best, top.first().map(|&(index, _)| index));
+                }
+                Err(_) => prop_assert!(needle.is_degenerate()),
+            }
+        }
+
+        #[test]
+        fn quantized_search_never_panics(
+            embeddings in any_embeddings(),
+            needle in any_embedding(),
+        ) {
+            let int8 = Int8Embeddings::from_embeddings(&embeddings);
+            let binary = BinaryEmbeddings::from_embeddings(&embeddings);
+
+            let best = int8.find_most_similar(&needle);
+            prop_assert_eq!(embeddings.is_empty(), best.is_none());
+            binary.find_most_similar(&needle);
+            binary.find_most_similar_rescored(&needle, 5, &embeddings);
+        }
+    }
 }
@@ -12,10 +12,40 @@ pub enum Error {
     MalformedXml(String),
     #[error("Error embedding something against the API {0}")]
     Embedding(String),
+    #[error("Io error accessing embedding cache")]
+    CacheIo(#[source] io::Error),
+    #[error("Embedding cache does not fit the embeddings requested: {0}")]
+    IncompatibleCache(String),
+    #[error("Embedding cache holds embeddings of a different representation: {0}")]
+    RepresentationChanged(String),
+    #[error(
+        "Embedding cache has been computed from different texts. Migrate it to reuse the \
+        embeddings of unchanged posts."
+    )]
+    CacheOutdated,
+    #[error(
+        "Embedding is all zeroes or contains values which are not finite. It can not be compared \
+        to other embeddings."
+    )]
+    DegenerateEmbedding,
+    #[error("Invalid index parameters: {0}")]
+    InvalidIndexParams(String),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("Invalid filter: {0}")]
+    InvalidFilter(String),
+    #[error("Invalid cursor: {0}")]
+    InvalidCursor(String),
+    #[error("There is no embedding for post {0}")]
+    UnknownPost(u64),
 }
 
 impl Error {
     pub fn invalid_xml(message: impl Into<String>) -> Self {
         Error::InvalidXml(message.into())
     }
+
+    pub fn incompatible_cache(message: impl Into<String>) -> Self {
+        Error::IncompatibleCache(message.into())
+    }
 }
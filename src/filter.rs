@@ -0,0 +1,110 @@
+use crate::{language::language_name, Document, Error};
+
+/// Condition on the metadata of a question, e.g. its tags or score. Evaluated alongside a search,
+/// so only matching questions are returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// Questions carrying the tag
+    Tag(String),
+    /// Questions with at least this score, i.e. upvotes minus downvotes
+    MinScore(i64),
+    /// Questions with an accepted answer
+    Answered,
+    /// Questions asked on or after the date. See [`Filter::since`].
+    Since(String),
+    /// Questions written in the language with this ISO 639-3 code. See [`Filter::language`].
+    Language(String),
+    /// Questions matching all of the filters. Matches every question, if empty.
+    And(Vec<Filter>),
+    /// Questions matching any of the filters. Matches no question, if empty.
+    Or(Vec<Filter>),
+    /// Questions not matching the filter
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    /// Questions asked on or after `date`, given as `YYYY`, `YYYY-MM` or `YYYY-MM-DD`. E.g. `2020`
+    /// is the first of January 2020.
+    pub fn since(date: &str) -> Result<Filter, Error> {
+        let valid = date.split('-').enumerate().all(|(position, part)| {
+            let len = if position == 0 { 4 } else { 2 };
+            position < 3 && part.len() == len && part.bytes().all(|byte| byte.is_ascii_digit())
+        });
+        if !valid {
+            return Err(Error::InvalidFilter(format!(
+                "Expected a date like 2020, 2020-06 or 2020-06-01, but found '{date}'"
+            )));
+        }
+        Ok(Filter::Since(date.to_owned()))
+    }
+
+    /// Questions written in the language with the ISO 639-3 `code`, e.g. `deu` for German.
+    pub fn language(code: &str) -> Result<Filter, Error> {
+        if language_name(code).is_none() {
+            return Err(Error::InvalidFilter(format!(
+                "Expected an ISO 639-3 language code like deu, por or rus, but found '{code}'"
+            )));
+        }
+        Ok(Filter::Language(code.to_owned()))
+    }
+
+    pub fn matches(&self, document: &Document) -> bool {
+        match self {
+            Filter::Tag(tag) => document.tags.contains(tag),
+            Filter::MinScore(score) => document.score >= *score,
+            Filter::Answered => document.answered,
+            // ISO 8601 timestamps sort like the points in time they describe, and a date prefix
+            // sorts before every timestamp starting with it.
+            Filter::Since(date) => document.creation_date.as_str() >= date.as_str(),
+            Filter::Language(code) => document.language == *code,
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(document)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(document)),
+            Filter::Not(filter) => !filter.matches(document),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use crate::{Chunking, Document};
+
+    fn document(score: i64, answered: bool, creation_date: &str) -> Document {
+        Document {
+            tags: vec!["safety".to_owned()],
+            score,
+            answered,
+            creation_date: creation_date.to_owned(),
+            ..Document::new(1, "Title".to_owned(), "", Chunking::whole())
+        }
+    }
+
+    #[test]
+    fn combine_metadata_conditions() {
+        let filter = Filter::And(vec![
+            Filter::Tag("safety".to_owned()),
+            Filter::MinScore(5),
+            Filter::Answered,
+            Filter::since("2020").unwrap(),
+        ]);
+
+        assert!(filter.matches(&document(5, true, "2020-01-01T00:00:00.000")));
+        assert!(!filter.matches(&document(4, true, "2021-03-04T10:00:00.000")));
+        assert!(!filter.matches(&document(5, false, "2021-03-04T10:00:00.000")));
+        assert!(!filter.matches(&document(5, true, "2019-12-31T23:59:59.999")));
+    }
+
+    #[test]
+    fn reject_malformed_dates() {
+        assert!(Filter::since("2020-06-01").is_ok());
+        assert!(Filter::since("20").is_err());
+        assert!(Filter::since("2020-6").is_err());
+        assert!(Filter::since("2020-06-01T12").is_err());
+    }
+
+    #[test]
+    fn reject_unknown_languages() {
+        assert!(Filter::language("por").is_ok());
+        assert!(Filter::language("pt").is_err());
+    }
+}
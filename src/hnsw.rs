@@ -0,0 +1,552 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+    io::{self, BufRead, Write},
+};
+
+use ordered_float::OrderedFloat;
+
+use crate::{
+    index::{read_u32, read_u64, VectorIndex},
+    kmeans::SplitMix64,
+    Embedding, Embeddings, Error,
+};
+
+/// Parameters controlling how a [`Hnsw`] graph is built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HnswParams {
+    /// Maximum number of neighbours of each node on the upper layers. The bottom layer allows
+    /// twice as many. Higher values increase recall, memory usage and build time.
+    pub m: usize,
+    /// Number of candidates considered while searching for the neighbours of a new node. Higher
+    /// values increase the quality of the graph and build time.
+    pub ef_construction: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 100,
+        }
+    }
+}
+
+/// First bytes of every persisted HNSW index.
+const MAGIC: &[u8; 8] = b"SSEHNSW1";
+/// Maximum number of nodes memory is reserved for, before reading them from a file.
+const MAX_PREALLOCATED_NODES: usize = 1 << 20;
+/// Upper bound for the number of layers, so a freak random number can not blow up the graph.
+const MAX_LAYERS: usize = 16;
+
+/// Hierarchical Navigable Small World graph. An approximate nearest neighbor index which finds
+/// similar embeddings by walking a graph, rather than comparing the needle with every single
+/// embedding. See <https://arxiv.org/abs/1603.09320>.
+///
+/// The graph only holds the indices of the embeddings. The embeddings themselves are passed to
+/// each search.
+#[derive(Debug, PartialEq)]
+pub struct Hnsw {
+    params: HnswParams,
+    /// Number of candidates considered during a search. Higher values increase recall and
+    /// latency.
+    ef: usize,
+    /// `links[node][layer]` holds the neighbours of a node on a layer. Nodes are part of all
+    /// layers up to their own level. Degenerate embeddings are not part of any layer.
+    links: Vec<Vec<Vec<u32>>>,
+    /// Node on the top layer every search starts with. `None` if the graph is empty.
+    entry_point: Option<u32>,
+    /// Fingerprint of the embeddings the graph has been built for.
+    fingerprint: u64,
+}
+
+impl Hnsw {
+    /// Builds a graph over all embeddings.
+    pub fn build(embeddings: &Embeddings, params: HnswParams) -> Self {
+        let mut hnsw = Hnsw {
+            params,
+            ef: 64,
+            links: Vec::with_capacity(embeddings.len()),
+            entry_point: None,
+            fingerprint: embeddings.fingerprint(),
+        };
+        let mut levels = LevelGenerator::new(params.m);
+        for node in 0..embeddings.len() {
+            if embeddings.records()[node].embedding.is_degenerate() {
+                hnsw.links.push(Vec::new());
+            } else {
+                hnsw.insert(embeddings, node as u32, levels.next());
+            }
+        }
+        hnsw
+    }
+
+    pub fn params(&self) -> HnswParams {
+        self.params
+    }
+
+    /// Sets the number of candidates considered during a search. At least `k` candidates are
+    /// always considered.
+    pub fn set_ef(&mut self, ef: usize) {
+        self.ef = ef;
+    }
+
+    /// `true` if the graph has been built for `embeddings`. If the embeddings changed since, the
+    /// graph needs to be built anew. Also `false` for a corrupt graph, which has a different
+    /// number of nodes than there are embeddings.
+    pub fn fits(&self, embeddings: &Embeddings) -> bool {
+        self.fingerprint == embeddings.fingerprint() && self.links.len() == embeddings.len()
+    }
+
+    fn insert(&mut self, embeddings: &Embeddings, node: u32, level: usize) {
+        self.links.push(vec![Vec::new(); level + 1]);
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            return;
+        };
+        let needle = &embeddings.records()[node as usize].embedding;
+        let top_layer = self.top_layer();
+        let mut entry_points = vec![entry_point];
+        for layer in (level + 1..=top_layer).rev() {
+            entry_points = self.closest(embeddings, needle, &entry_points, 1, layer);
+        }
+        for layer in (0..=level.min(top_layer)).rev() {
+            let candidates = self.search_layer(
+                embeddings,
+                needle,
+                &entry_points,
+                self.params.ef_construction,
+                layer,
+            );
+            let neighbours = select_neighbours(embeddings, &candidates, self.max_links(layer));
+            for &neighbour in &neighbours {
+                let links = &mut self.links[neighbour as usize][layer];
+                links.push(node);
+                if links.len() > self.max_links(layer) {
+                    self.prune(embeddings, neighbour, layer);
+                }
+            }
+            self.links[node as usize][layer] = neighbours;
+            entry_points = candidates.into_iter().map(|(_, node)| node).collect();
+        }
+        if level > top_layer {
+            self.entry_point = Some(node);
+        }
+    }
+
+    /// Drops the links of `node` on `layer` which contribute least to the connectivity of the
+    /// graph, so it is left with at most the maximum number of links.
+    fn prune(&mut self, embeddings: &Embeddings, node: u32, layer: usize) {
+        let base = &embeddings.records()[node as usize].embedding;
+        let mut candidates: Vec<_> = self.links[node as usize][layer]
+            .iter()
+            .map(|&other| (similarity(embeddings, base, other), other))
+            .collect();
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+        self.links[node as usize][layer] =
+            select_neighbours(embeddings, &candidates, self.max_links(layer));
+    }
+
+    fn max_links(&self, layer: usize) -> usize {
+        if layer == 0 {
+            2 * self.params.m
+        } else {
+            self.params.m
+        }
+    }
+
+    fn top_layer(&self) -> usize {
+        self.entry_point
+            .map(|node| self.links[node as usize].len() - 1)
+            .unwrap_or(0)
+    }
+
+    /// The `n` nodes on `layer` most similar to `needle`, most similar first.
+    fn closest(
+        &self,
+        embeddings: &Embeddings,
+        needle: &Embedding,
+        entry_points: &[u32],
+        n: usize,
+        layer: usize,
+    ) -> Vec<u32> {
+        self.search_layer(embeddings, needle, entry_points, n, layer)
+            .into_iter()
+            .map(|(_, node)| node)
+            .collect()
+    }
+
+    /// Greedy best first search on a single layer. Returns up to `ef` tuples of similarity and
+    /// node, most similar first.
+    fn search_layer(
+        &self,
+        embeddings: &Embeddings,
+        needle: &Embedding,
+        entry_points: &[u32],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<(f32, u32)> {
+        let mut visited: HashSet<u32> = entry_points.iter().copied().collect();
+        // Most similar candidate on top
+        let mut candidates = BinaryHeap::new();
+        // Least similar result on top, so it can be replaced by better ones
+        let mut results = BinaryHeap::new();
+        for &node in entry_points {
+            let similarity = OrderedFloat(similarity(embeddings, needle, node));
+            candidates.push((similarity, node));
+            results.push(Reverse((similarity, node)));
+            if results.len() > ef {
+                results.pop();
+            }
+        }
+        while let Some((similarity, node)) = candidates.pop() {
+            let Reverse((worst, _)) = *results.peek().unwrap();
+            if similarity < worst && results.len() >= ef {
+                break;
+            }
+            for &neighbour in &self.links[node as usize][layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let similarity = OrderedFloat(self::similarity(embeddings, needle, neighbour));
+                let Reverse((worst, _)) = *results.peek().unwrap();
+                if results.len() < ef || similarity > worst {
+                    candidates.push((similarity, neighbour));
+                    results.push(Reverse((similarity, neighbour)));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        results
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((OrderedFloat(similarity), node))| (similarity, node))
+            .collect()
+    }
+
+    pub fn write(&self, write: &mut impl Write) -> Result<(), io::Error> {
+        write.write_all(MAGIC)?;
+        write.write_all(&(self.params.m as u32).to_le_bytes())?;
+        write.write_all(&(self.params.ef_construction as u32).to_le_bytes())?;
+        write.write_all(&self.fingerprint.to_le_bytes())?;
+        write.write_all(&(self.links.len() as u64).to_le_bytes())?;
+        let entry_point = self.entry_point.map(u64::from).unwrap_or(u64::MAX);
+        write.write_all(&entry_point.to_le_bytes())?;
+        for layers in &self.links {
+            write.write_all(&[layers.len() as u8])?;
+            for links in layers {
+                write.write_all(&(links.len() as u32).to_le_bytes())?;
+                for link in links {
+                    write.write_all(&link.to_le_bytes())?;
+                }
+            }
+        }
+        write.flush()
+    }
+
+    pub fn from_reader(read: &mut impl BufRead) -> Result<Self, io::Error> {
+        let mut magic = [0u8; 8];
+        read.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "File does not contain an HNSW index.",
+            ));
+        }
+        let m = read_u32(read)? as usize;
+        let ef_construction = read_u32(read)? as usize;
+        let fingerprint = read_u64(read)?;
+        // Nodes are referred to by `u32`, so there can not be more of them.
+        let num_nodes = read_u64(read)?;
+        if num_nodes > u64::from(u32::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HNSW index claims more nodes than it can address.",
+            ));
+        }
+        let num_nodes = num_nodes as usize;
+        let entry_point = match read_u64(read)? {
+            u64::MAX => None,
+            node if node < num_nodes as u64 => Some(node as u32),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "HNSW index enters the graph at a node which does not exist.",
+                ))
+            }
+        };
+        // The count is not trusted until the nodes have actually been read, so a corrupt file
+        // can not make us allocate huge amounts of memory upfront.
+        let mut links = Vec::with_capacity(num_nodes.min(MAX_PREALLOCATED_NODES));
+        for _ in 0..num_nodes {
+            let mut num_layers = [0u8];
+            read.read_exact(&mut num_layers)?;
+            let mut layers = Vec::with_capacity(num_layers[0] as usize);
+            for _ in 0..num_layers[0] {
+                let num_links = read_u32(read)?;
+                let layer = (0..num_links)
+                    .map(|_| read_u32(read))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if layer.iter().any(|&link| link as usize >= num_nodes) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "HNSW index links to a node which does not exist.",
+                    ));
+                }
+                layers.push(layer);
+            }
+            links.push(layers);
+        }
+        // Searches descend from the top layer of the entry point and follow links on each layer,
+        // so every node reached that way must be part of the layer.
+        let top_layers = entry_point.map_or(0, |node| links[node as usize].len());
+        if entry_point.is_some() && top_layers == 0
+            || links.iter().any(|layers| layers.len() > top_layers)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HNSW index enters the graph below its top layer.",
+            ));
+        }
+        let links_valid = links.iter().all(|layers| {
+            layers.iter().enumerate().all(|(layer, neighbours)| {
+                neighbours
+                    .iter()
+                    .all(|&neighbour| links[neighbour as usize].len() > layer)
+            })
+        });
+        if !links_valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HNSW index links to a node on a layer the node is not part of.",
+            ));
+        }
+        Ok(Self {
+            params: HnswParams { m, ef_construction },
+            ef: 64,
+            links,
+            entry_point,
+            fingerprint,
+        })
+    }
+}
+
+impl VectorIndex for Hnsw {
+    fn search(
+        &self,
+        embeddings: &Embeddings,
+        needle: &Embedding,
+        k: usize,
+    ) -> Result<Vec<(usize, f32)>, Error> {
+        if needle.is_degenerate() {
+            return Err(Error::DegenerateEmbedding);
+        }
+        let Some(entry_point) = self.entry_point else {
+            return Ok(Vec::new());
+        };
+        let needle = needle.normalize();
+        let mut entry_points = vec![entry_point];
+        for layer in (1..=self.top_layer()).rev() {
+            entry_points = self.closest(embeddings, &needle, &entry_points, 1, layer);
+        }
+        let mut found = self.search_layer(embeddings, &needle, &entry_points, self.ef.max(k), 0);
+        found.truncate(k);
+        Ok(found
+            .into_iter()
+            .map(|(similarity, node)| (node as usize, similarity))
+            .collect())
+    }
+}
+
+fn similarity(embeddings: &Embeddings, needle: &Embedding, node: u32) -> f32 {
+    embeddings.records()[node as usize].embedding.dot(needle)
+}
+
+/// Picks up to `m` neighbours for a node from `candidates`, which are tuples of similarity to the
+/// node and candidate, most similar first. A candidate is skipped, if it is more similar to an
+/// already picked neighbour than to the node, so the links point into different directions.
+/// Skipped candidates fill up the remaining slots.
+fn select_neighbours(embeddings: &Embeddings, candidates: &[(f32, u32)], m: usize) -> Vec<u32> {
+    let mut selected: Vec<u32> = Vec::with_capacity(m);
+    let mut skipped = Vec::new();
+    for &(similarity_to_base, candidate) in candidates {
+        if selected.len() == m {
+            break;
+        }
+        let vector = &embeddings.records()[candidate as usize].embedding;
+        let diverse = selected
+            .iter()
+            .all(|&other| similarity(embeddings, vector, other) < similarity_to_base);
+        if diverse {
+            selected.push(candidate);
+        } else {
+            skipped.push(candidate);
+        }
+    }
+    let missing = m - selected.len();
+    selected.extend(skipped.into_iter().take(missing));
+    selected
+}
+
+/// Draws the level of each new node from an exponentially decaying distribution, so each layer
+/// holds about `1/m` of the nodes of the layer below. Uses a fixed seed, so building the same graph
+/// twice yields the same result.
+struct LevelGenerator {
+    rng: SplitMix64,
+    /// Normalization factor of the level distribution
+    ml: f64,
+}
+
+impl LevelGenerator {
+    fn new(m: usize) -> Self {
+        Self {
+            rng: SplitMix64::new(0x9e3779b97f4a7c15),
+            ml: 1. / (m.max(2) as f64).ln(),
+        }
+    }
+
+    fn next(&mut self) -> usize {
+        let uniform = self.rng.next_f64();
+        ((-uniform.ln() * self.ml) as usize).min(MAX_LAYERS - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        embedding::EMBEDDING_SIZE,
+        index::recall_at_k,
+        test_util::{random_embedding, random_embeddings},
+        Record,
+    };
+
+    use super::*;
+
+    /// Smaller than the defaults, to keep the tests fast in debug builds
+    fn small_params() -> HnswParams {
+        HnswParams {
+            m: 8,
+            ef_construction: 40,
+        }
+    }
+
+    #[test]
+    fn recall_compared_to_exact_search() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let embeddings = random_embeddings(&mut rng, 1_000);
+        let needles: Vec<_> = (0..20).map(|_| random_embedding(&mut rng)).collect();
+
+        let hnsw = Hnsw::build(&embeddings, small_params());
+        let recall = recall_at_k(&hnsw, &embeddings, &needles, 10).unwrap();
+
+        assert!(recall > 0.9, "Recall is only {recall}");
+    }
+
+    #[test]
+    fn finds_itself() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let embeddings = random_embeddings(&mut rng, 500);
+
+        let hnsw = Hnsw::build(&embeddings, small_params());
+
+        let found = hnsw
+            .search(&embeddings, &embeddings.records()[123].embedding, 1)
+            .unwrap();
+        assert_eq!(123, found[0].0);
+    }
+
+    #[test]
+    fn skips_degenerate_embeddings() {
+        let embeddings = Embeddings::from_vec(vec![
+            Record::default(),
+            Record {
+                id: 1,
+                text_hash: 0,
+                embedding: Embedding([1.; EMBEDDING_SIZE]),
+            },
+        ]);
+
+        let hnsw = Hnsw::build(&embeddings, HnswParams::default());
+        let found = hnsw
+            .search(&embeddings, &Embedding([1.; EMBEDDING_SIZE]), 5)
+            .unwrap();
+
+        assert_eq!(
+            vec![1],
+            found.iter().map(|(index, _)| *index).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn hnsw_to_and_fro_bytes() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let embeddings = random_embeddings(&mut rng, 300);
+        let hnsw = Hnsw::build(
+            &embeddings,
+            HnswParams {
+                m: 8,
+                ef_construction: 50,
+            },
+        );
+
+        let mut buf = Vec::new();
+        hnsw.write(&mut buf).unwrap();
+        let loaded = Hnsw::from_reader(&mut Cursor::new(buf)).unwrap();
+
+        assert_eq!(hnsw, loaded);
+        assert!(loaded.fits(&embeddings));
+    }
+
+    #[test]
+    fn reject_corrupt_node_count_and_entry_point() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let embeddings = random_embeddings(&mut rng, 10);
+        let mut buf = Vec::new();
+        Hnsw::build(&embeddings, small_params())
+            .write(&mut buf)
+            .unwrap();
+        // Offsets of the number of nodes and of the entry point, following magic bytes, m,
+        // ef_construction and fingerprint.
+        let read_patched = |offset: usize, value: u64| {
+            let mut buf = buf.clone();
+            buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            Hnsw::from_reader(&mut Cursor::new(buf)).unwrap_err().kind()
+        };
+
+        assert_eq!(io::ErrorKind::InvalidData, read_patched(24, u64::MAX - 1));
+        assert_eq!(io::ErrorKind::InvalidData, read_patched(32, 10));
+    }
+    #[test]
+    fn reject_links_to_missing_layers() {
+        let read_written = |links: Vec<Vec<Vec<u32>>>| {
+            let hnsw = Hnsw {
+                params: small_params(),
+                ef: 64,
+                links,
+                entry_point: Some(0),
+                fingerprint: 0,
+            };
+            let mut buf = Vec::new();
+            hnsw.write(&mut buf).unwrap();
+            Hnsw::from_reader(&mut Cursor::new(buf)).map(|_| ())
+        };
+
+        // Valid: both nodes on layer 0, the entry point also on layer 1
+        assert!(read_written(vec![vec![vec![1], vec![]], vec![vec![0]]]).is_ok());
+        // Entry point without any layer
+        let error = read_written(vec![vec![], vec![vec![]]]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        // Entry point below the top layer
+        let error = read_written(vec![vec![vec![]], vec![vec![], vec![]]]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        // Link on layer 1 to a node only part of layer 0
+        let error = read_written(vec![vec![vec![1], vec![1]], vec![vec![0]]]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+/// How the rankings of lexical and semantic search are combined into a single one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fusion {
+    /// Reciprocal rank fusion. Each post scores `1 / (k + rank)` in each ranking it appears in.
+    /// Only ranks matter, so BM25 scores and similarities need not be comparable. See
+    /// <https://plg.uwaterloo.ca/~gvcormac/cormacksigir09-rrf.pdf>.
+    ReciprocalRank { k: f32 },
+    /// Scores of each ranking are scaled to `[0, 1]` and added up, weighing lexical scores with
+    /// `lexical_weight` and semantic scores with `1 - lexical_weight`.
+    Weighted { lexical_weight: f32 },
+}
+
+impl Default for Fusion {
+    fn default() -> Self {
+        Fusion::ReciprocalRank { k: 60. }
+    }
+}
+
+impl Fusion {
+    /// Fuses two rankings, each given as tuples of post id and score, best first. Returns the best
+    /// `k` posts with their fused score, best first. Ties are broken in favour of the lower post
+    /// id.
+    pub fn fuse(
+        &self,
+        lexical: &[(u64, f32)],
+        semantic: &[(u64, f32)],
+        k: usize,
+    ) -> Vec<(u64, f32)> {
+        let mut fused: HashMap<u64, f32> = HashMap::new();
+        match *self {
+            Fusion::ReciprocalRank { k } => {
+                for ranking in [lexical, semantic] {
+                    for (rank, &(id, _)) in ranking.iter().enumerate() {
+                        *fused.entry(id).or_default() += 1. / (k + rank as f32 + 1.);
+                    }
+                }
+            }
+            Fusion::Weighted { lexical_weight } => {
+                for (ranking, weight) in
+                    [(lexical, lexical_weight), (semantic, 1. - lexical_weight)]
+                {
+                    let mut scores: Vec<f32> = ranking.iter().map(|&(_, score)| score).collect();
+                    min_max(&mut scores);
+                    for (&(id, _), score) in ranking.iter().zip(scores) {
+                        *fused.entry(id).or_default() += weight * score;
+                    }
+                }
+            }
+        }
+        let mut fused: Vec<_> = fused.into_iter().collect();
+        fused.sort_by(|(a_id, a), (b_id, b)| b.total_cmp(a).then(a_id.cmp(b_id)));
+        fused.truncate(k);
+        fused
+    }
+}
+
+/// Scales `scores` linearly, so the best one is `1` and the worst one is `0`. All scores are `1`,
+/// if they are equal.
+pub(crate) fn min_max(scores: &mut [f32]) {
+    let max = scores.iter().copied().fold(f32::MIN, f32::max);
+    let min = scores.iter().copied().fold(f32::MAX, f32::min);
+    for score in scores {
+        *score = if max > min {
+            (*score - min) / (max - min)
+        } else {
+            1.
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fusion;
+
+    #[test]
+    fn reciprocal_rank_prefers_posts_found_by_both() {
+        let lexical = [(1, 12.), (2, 7.)];
+        let semantic = [(3, 0.9), (2, 0.8)];
+
+        let fused = Fusion::default().fuse(&lexical, &semantic, 3);
+
+        let ids: Vec<_> = fused.iter().map(|(id, _)| *id).collect();
+        assert_eq!(vec![2, 1, 3], ids);
+    }
+
+    #[test]
+    fn weighted_by_normalized_scores() {
+        let lexical = [(1, 12.), (2, 6.), (3, 0.)];
+        let semantic = [(3, 0.9), (2, 0.5), (1, 0.1)];
+
+        let fused = Fusion::Weighted {
+            lexical_weight: 0.75,
+        }
+        .fuse(&lexical, &semantic, 3);
+
+        let ids: Vec<_> = fused.iter().map(|(id, _)| *id).collect();
+        assert_eq!(vec![1, 2, 3], ids);
+        assert_eq!(0.75, fused[0].1);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufRead},
+};
+
+use crate::{embedding::top_k, Embedding, Embeddings, Error};
+
+/// Finds the embeddings most similar to a needle. Implemented by the exact search and by
+/// approximate nearest neighbor indices, which trade some recall for speed.
+pub trait VectorIndex {
+    /// The `k` embeddings most similar to `needle` as tuples of index and similarity. Most similar
+    /// first. `embeddings` must be the ones the index has been built for. Fails if `needle` is
+    /// degenerate.
+    fn search(
+        &self,
+        embeddings: &Embeddings,
+        needle: &Embedding,
+        k: usize,
+    ) -> Result<Vec<(usize, f32)>, Error>;
+}
+
+/// Compares the needle with every single embedding. Slow for large communities, but always finds
+/// the most similar embeddings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExactSearch;
+
+impl VectorIndex for ExactSearch {
+    fn search(
+        &self,
+        embeddings: &Embeddings,
+        needle: &Embedding,
+        k: usize,
+    ) -> Result<Vec<(usize, f32)>, Error> {
+        embeddings.find_top_k(needle, k)
+    }
+}
+
+/// The `k` posts with the embeddings most similar to `needle`, as tuples of post id and similarity.
+/// Most similar first, ties ordered by id. A post with several embeddings, e.g. one for each chunk
+/// of a long text, is scored by its most similar embedding.
+pub fn search_posts(
+    index: &dyn VectorIndex,
+    embeddings: &Embeddings,
+    needle: &Embedding,
+    k: usize,
+) -> Result<Vec<(u64, f32)>, Error> {
+    let posts = search_post_embeddings(index, embeddings, needle, k)?
+        .into_iter()
+        .map(|(index, similarity)| (embeddings.id(index), similarity))
+        .collect();
+    Ok(posts)
+}
+
+/// The `k` posts most similar to the post with the given `id`, as tuples of post id and
+/// similarity. Most similar first, ties ordered by id. Uses the stored embeddings of the post, so
+/// no API call is needed. The post itself is excluded. A post with several embeddings is compared
+/// by its most similar pair of embeddings. Fails if there is no embedding for `id`.
+pub fn similar_posts(
+    index: &dyn VectorIndex,
+    embeddings: &Embeddings,
+    id: u64,
+    k: usize,
+) -> Result<Vec<(u64, f32)>, Error> {
+    let needles: Vec<&Embedding> = embeddings
+        .records()
+        .iter()
+        .filter(|record| record.id == id)
+        .map(|record| &record.embedding)
+        .collect();
+    if needles.is_empty() {
+        return Err(Error::UnknownPost(id));
+    }
+    let mut best: HashMap<u64, f32> = HashMap::new();
+    for needle in needles {
+        // The post finds itself, too.
+        for (other, similarity) in search_posts(index, embeddings, needle, k + 1)? {
+            if other != id {
+                let best = best.entry(other).or_insert(similarity);
+                *best = best.max(similarity);
+            }
+        }
+    }
+    let mut similar: Vec<_> = best.into_iter().collect();
+    similar.sort_by(|(a_id, a), (b_id, b)| b.total_cmp(a).then(a_id.cmp(b_id)));
+    similar.truncate(k);
+    Ok(similar)
+}
+
+/// Like [`search_posts`], but returns the index of the most similar embedding of each post instead
+/// of its id. Use [`Embeddings::id`] to learn which post it belongs to.
+pub fn search_post_embeddings(
+    index: &dyn VectorIndex,
+    embeddings: &Embeddings,
+    needle: &Embedding,
+    k: usize,
+) -> Result<Vec<(usize, f32)>, Error> {
+    let mut num_embeddings = k;
+    loop {
+        let matches = index.search(embeddings, needle, num_embeddings)?;
+        let exhausted = matches.len() < num_embeddings;
+        let mut seen = HashSet::new();
+        // Matches are sorted, so the first embedding of each post is its most similar one.
+        let mut posts: Vec<_> = matches
+            .into_iter()
+            .filter(|&(index, _)| seen.insert(embeddings.id(index)))
+            .collect();
+        if posts.len() >= k || exhausted {
+            // Approximate indices do not order ties, so results are stable across searches.
+            posts.sort_by(|(a_index, a), (b_index, b)| {
+                b.total_cmp(a)
+                    .then(embeddings.id(*a_index).cmp(&embeddings.id(*b_index)))
+            });
+            posts.truncate(k);
+            return Ok(posts);
+        }
+        // Some posts have been found more than once. Look further.
+        num_embeddings *= 2;
+    }
+}
+
+/// Share of embeddings kept by a filter, below which [`search_post_embeddings_where`] compares the
+/// needle with the kept embeddings only, rather than filtering the results of the index.
+const PRE_FILTER_SELECTIVITY: f32 = 0.1;
+
+/// Like [`search_post_embeddings`], but only considers posts for which `keep` returns `true`.
+///
+/// Filters keeping few posts are applied before the search. The needle is compared with each kept
+/// embedding, which is exact and cheap, since there are few of them. Otherwise the results of the
+/// index are filtered, fetching more of them until there are `k` posts left, so approximate
+/// indices keep their speed.
+pub fn search_post_embeddings_where(
+    index: &dyn VectorIndex,
+    embeddings: &Embeddings,
+    needle: &Embedding,
+    k: usize,
+    keep: impl Fn(u64) -> bool,
+) -> Result<Vec<(usize, f32)>, Error> {
+    let records = embeddings.records();
+    let kept = records.iter().filter(|record| keep(record.id)).count();
+    if (kept as f32) < PRE_FILTER_SELECTIVITY * records.len() as f32 {
+        if needle.is_degenerate() {
+            return Err(Error::DegenerateEmbedding);
+        }
+        let needle = needle.normalize();
+        // Most similar embedding of each kept post. Degenerate embeddings are stored as NaN.
+        let mut best: HashMap<u64, (usize, f32)> = HashMap::new();
+        for (position, record) in records.iter().enumerate() {
+            let similarity = record.embedding.dot(&needle);
+            if !keep(record.id) || similarity.is_nan() {
+                continue;
+            }
+            let best = best.entry(record.id).or_insert((position, similarity));
+            if similarity > best.1 {
+                *best = (position, similarity);
+            }
+        }
+        let best = best
+            .into_values()
+            .map(|(position, similarity)| ((records[position].id, position), similarity));
+        return Ok(top_k(best, k)
+            .into_iter()
+            .map(|((_id, position), similarity)| (position, similarity))
+            .collect());
+    }
+
+    let mut num_posts = k;
+    loop {
+        let posts = search_post_embeddings(index, embeddings, needle, num_posts)?;
+        let exhausted = posts.len() < num_posts;
+        let mut posts: Vec<_> = posts
+            .into_iter()
+            .filter(|&(position, _)| keep(embeddings.id(position)))
+            .collect();
+        if posts.len() >= k || exhausted {
+            posts.truncate(k);
+            return Ok(posts);
+        }
+        // Some posts have been filtered out. Look further.
+        num_posts *= 2;
+    }
+}
+
+/// Share of the `k` most similar embeddings found by the exact search, which are also found by
+/// `index`. Averaged over all `needles`. Used to check the quality of approximate indices.
+pub fn recall_at_k(
+    index: &dyn VectorIndex,
+    embeddings: &Embeddings,
+    needles: &[Embedding],
+    k: usize,
+) -> Result<f64, Error> {
+    let mut found = 0;
+    let mut expected = 0;
+    for needle in needles {
+        let exact: HashSet<_> = embeddings
+            .find_top_k(needle, k)?
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        found += index
+            .search(embeddings, needle, k)?
+            .into_iter()
+            .filter(|(index, _)| exact.contains(index))
+            .count();
+        expected += exact.len();
+    }
+    if expected == 0 {
+        return Ok(1.);
+    }
+    Ok(found as f64 / expected as f64)
+}
+
+pub(crate) fn read_u32(read: &mut impl BufRead) -> Result<u32, io::Error> {
+    let mut buf = [0u8; 4];
+    read.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u64(read: &mut impl BufRead) -> Result<u64, io::Error> {
+    let mut buf = [0u8; 8];
+    read.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub(crate) fn read_f32(read: &mut impl BufRead) -> Result<f32, io::Error> {
+    let mut buf = [0u8; 4];
+    read.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Embedding, Embeddings, Error, Record, EMBEDDING_SIZE};
+
+    use super::{search_post_embeddings_where, search_posts, similar_posts, ExactSearch};
+
+    fn record(id: u64, direction: usize) -> Record {
+        let mut embedding = Embedding([0.; EMBEDDING_SIZE]);
+        embedding.0[direction] = 1.;
+        embedding.0[0] += 0.1;
+        Record {
+            id,
+            text_hash: direction as u64,
+            embedding,
+        }
+    }
+
+    #[test]
+    fn score_post_by_its_most_similar_chunk() {
+        // Post 1 has two chunks, which are both more similar to the needle than post 2.
+        let embeddings = Embeddings::from_vec(vec![record(1, 0), record(1, 0), record(2, 1)]);
+        let mut needle = Embedding([0.; EMBEDDING_SIZE]);
+        needle.0[0] = 1.;
+
+        let posts = search_posts(&ExactSearch, &embeddings, &needle, 2).unwrap();
+
+        assert_eq!(
+            vec![1, 2],
+            posts.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        assert!(posts[0].1 > posts[1].1);
+    }
+
+    #[test]
+    fn similar_posts_exclude_the_post_itself() {
+        let embeddings = Embeddings::from_vec(vec![record(1, 1), record(2, 2), record(3, 1)]);
+
+        let posts = similar_posts(&ExactSearch, &embeddings, 1, 2).unwrap();
+
+        assert_eq!(
+            vec![3, 2],
+            posts.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            similar_posts(&ExactSearch, &embeddings, 4, 2),
+            Err(Error::UnknownPost(4))
+        ));
+    }
+
+    #[test]
+    fn filter_before_or_after_searching() {
+        let embeddings = Embeddings::from_vec((1..=20).map(|id| record(id, id as usize)).collect());
+        let mut needle = Embedding([0.; EMBEDDING_SIZE]);
+        needle.0[3] = 1.;
+        needle.0[4] = 0.5;
+        let ids = |posts: Vec<(usize, f32)>| {
+            posts
+                .into_iter()
+                .map(|(index, _)| embeddings.id(index))
+                .collect::<Vec<_>>()
+        };
+
+        // Keeps half of the posts, so the results of the index are filtered
+        let even =
+            search_post_embeddings_where(&ExactSearch, &embeddings, &needle, 1, |id| id % 2 == 0);
+        // Keeps a single post, so only its embedding is compared with the needle
+        let single =
+            search_post_embeddings_where(&ExactSearch, &embeddings, &needle, 1, |id| id == 4);
+
+        assert_eq!(vec![4], ids(even.unwrap()));
+        assert_eq!(vec![4], ids(single.unwrap()));
+    }
+}
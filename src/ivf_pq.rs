@@ -0,0 +1,540 @@
+use std::io::{self, BufRead, Read, Write};
+
+use rayon::prelude::*;
+
+use crate::{
+    embedding::{top_k, EMBEDDING_SIZE},
+    index::{read_f32, read_u32, read_u64, VectorIndex},
+    kmeans::{kmeans, nearest, SplitMix64},
+    Embedding, Embeddings, Error,
+};
+
+/// Parameters controlling how an [`IvfPq`] index is trained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IvfPqParams {
+    /// Number of inverted lists, i.e. coarse centroids. Each search only scans the lists closest
+    /// to the needle.
+    pub lists: usize,
+    /// Number of subspaces each embedding is split into. Each subspace is stored in one byte.
+    /// Must divide [`EMBEDDING_SIZE`]. Higher values increase recall and memory usage.
+    pub subspaces: usize,
+    /// Number of embeddings drawn to train the centroids and codebooks.
+    pub training_sample: usize,
+    /// Number of k-means iterations during training.
+    pub iterations: usize,
+}
+
+impl Default for IvfPqParams {
+    fn default() -> Self {
+        Self {
+            lists: 1024,
+            subspaces: 16,
+            training_sample: 65_536,
+            iterations: 10,
+        }
+    }
+}
+
+/// First bytes of every persisted IVF-PQ index.
+const MAGIC: &[u8; 8] = b"SSEIVFPQ";
+/// Number of codewords in the codebook of each subspace, so each code fits into a byte.
+const CODEBOOK_SIZE: usize = 256;
+
+/// Inverted file index with product quantization. An approximate nearest neighbor index for
+/// communities too large to keep a graph in memory. See <https://hal.inria.fr/inria-00514462>.
+///
+/// Each embedding is assigned to the list of its closest coarse centroid. Its residual to that
+/// centroid is split into subspaces, and each subspace is replaced by the index of the closest
+/// codeword. A search only scans the lists with the centroids most similar to the needle, and
+/// estimates similarities from the codes. Optionally the best candidates are reranked using the
+/// full precision embeddings.
+#[derive(Debug, PartialEq)]
+pub struct IvfPq {
+    params: IvfPqParams,
+    /// Number of lists scanned during a search.
+    nprobe: usize,
+    /// Number of candidates reranked with full precision embeddings. `0` disables reranking.
+    rerank: usize,
+    /// Coarse centroids one after another. There are fewer than `params.lists` of them, if there
+    /// have been fewer embeddings to train on.
+    centroids: Vec<f32>,
+    /// Codewords of each subspace one after another.
+    codebooks: Vec<Vec<f32>>,
+    lists: Vec<InvertedList>,
+    /// Fingerprint of the embeddings the index has been built for.
+    fingerprint: u64,
+}
+
+/// Embeddings assigned to one coarse centroid.
+#[derive(Debug, Default, PartialEq)]
+struct InvertedList {
+    nodes: Vec<u32>,
+    /// `subspaces` codes for each node.
+    codes: Vec<u8>,
+}
+
+impl IvfPq {
+    /// Trains centroids and codebooks on a sample of `embeddings` and encodes all of them.
+    /// Degenerate embeddings are not part of any list. Fails with
+    /// [`Error::InvalidIndexParams`], if there are no lists, or `params.subspaces` does not divide
+    /// [`EMBEDDING_SIZE`].
+    pub fn build(embeddings: &Embeddings, params: IvfPqParams) -> Result<Self, Error> {
+        if params.lists == 0 {
+            return Err(Error::InvalidIndexParams(
+                "IVF-PQ index needs at least one list.".to_owned(),
+            ));
+        }
+        if params.subspaces == 0 || !EMBEDDING_SIZE.is_multiple_of(params.subspaces) {
+            return Err(Error::InvalidIndexParams(format!(
+                "Number of subspaces must divide the embedding size of {EMBEDDING_SIZE}."
+            )));
+        }
+        let sub_dim = EMBEDDING_SIZE / params.subspaces;
+        let mut rng = SplitMix64::new(0x9e3779b97f4a7c15);
+        let nodes: Vec<u32> = (0..embeddings.len() as u32)
+            .filter(|&node| !vector(embeddings, node).is_degenerate())
+            .collect();
+        let sample: Vec<u32> = rng
+            .sample(nodes.len(), params.training_sample)
+            .into_iter()
+            .map(|index| nodes[index])
+            .collect();
+
+        let points: Vec<f32> = sample
+            .iter()
+            .flat_map(|&node| vector(embeddings, node).0)
+            .collect();
+        let centroids = kmeans(
+            &points,
+            EMBEDDING_SIZE,
+            params.lists,
+            params.iterations,
+            &mut rng,
+        );
+
+        let residuals: Vec<f32> = points
+            .chunks_exact(EMBEDDING_SIZE)
+            .flat_map(|point| residual(&centroids, point).1)
+            .collect();
+        let codebooks = (0..params.subspaces)
+            .map(|subspace| {
+                let sub_points: Vec<f32> = residuals
+                    .chunks_exact(EMBEDDING_SIZE)
+                    .flat_map(|residual| &residual[subspace * sub_dim..(subspace + 1) * sub_dim])
+                    .copied()
+                    .collect();
+                kmeans(
+                    &sub_points,
+                    sub_dim,
+                    CODEBOOK_SIZE,
+                    params.iterations,
+                    &mut rng,
+                )
+            })
+            .collect();
+
+        let mut ivf_pq = IvfPq {
+            params,
+            nprobe: 8,
+            rerank: 0,
+            lists: Vec::new(),
+            centroids,
+            codebooks,
+            fingerprint: embeddings.fingerprint(),
+        };
+        let encoded: Vec<(usize, Vec<u8>)> = nodes
+            .par_iter()
+            .map(|&node| ivf_pq.encode(&vector(embeddings, node).0))
+            .collect();
+        ivf_pq.lists = (0..ivf_pq.num_lists())
+            .map(|_| InvertedList::default())
+            .collect();
+        for (&node, (list, codes)) in nodes.iter().zip(encoded) {
+            ivf_pq.lists[list].nodes.push(node);
+            ivf_pq.lists[list].codes.extend(codes);
+        }
+        Ok(ivf_pq)
+    }
+
+    pub fn params(&self) -> IvfPqParams {
+        self.params
+    }
+
+    /// Sets the number of lists scanned during a search. Higher values increase recall and
+    /// latency.
+    pub fn set_nprobe(&mut self, nprobe: usize) {
+        self.nprobe = nprobe;
+    }
+
+    /// Sets the number of candidates, which are reranked using the full precision embeddings.
+    /// At least `k` candidates are reranked. `0` disables reranking, so the embeddings are not
+    /// touched at all and similarities are only estimates.
+    pub fn set_rerank(&mut self, rerank: usize) {
+        self.rerank = rerank;
+    }
+
+    /// `true` if the index has been built for `embeddings`. If the embeddings changed since, the
+    /// index needs to be built anew. Also `false` for a corrupt index, which refers to nodes
+    /// beyond the embeddings.
+    pub fn fits(&self, embeddings: &Embeddings) -> bool {
+        self.fingerprint == embeddings.fingerprint()
+            && self
+                .lists
+                .iter()
+                .flat_map(|list| &list.nodes)
+                .all(|&node| (node as usize) < embeddings.len())
+    }
+
+    fn num_lists(&self) -> usize {
+        self.centroids.len() / EMBEDDING_SIZE
+    }
+
+    fn sub_dim(&self) -> usize {
+        EMBEDDING_SIZE / self.params.subspaces
+    }
+
+    /// Index of the list `vector` belongs to and its codes.
+    fn encode(&self, vector: &[f32]) -> (usize, Vec<u8>) {
+        let (list, residual) = residual(&self.centroids, vector);
+        let codes = residual
+            .chunks_exact(self.sub_dim())
+            .zip(&self.codebooks)
+            .map(|(sub_vector, codebook)| nearest(codebook, self.sub_dim(), sub_vector) as u8)
+            .collect();
+        (list, codes)
+    }
+
+    pub fn write(&self, write: &mut impl Write) -> Result<(), io::Error> {
+        write.write_all(MAGIC)?;
+        write.write_all(&(self.params.lists as u32).to_le_bytes())?;
+        write.write_all(&(self.params.subspaces as u32).to_le_bytes())?;
+        write.write_all(&(self.params.training_sample as u32).to_le_bytes())?;
+        write.write_all(&(self.params.iterations as u32).to_le_bytes())?;
+        write.write_all(&self.fingerprint.to_le_bytes())?;
+        write_f32s(write, &self.centroids)?;
+        for codebook in &self.codebooks {
+            write_f32s(write, codebook)?;
+        }
+        for list in &self.lists {
+            write.write_all(&(list.nodes.len() as u32).to_le_bytes())?;
+            for node in &list.nodes {
+                write.write_all(&node.to_le_bytes())?;
+            }
+            write.write_all(&list.codes)?;
+        }
+        write.flush()
+    }
+
+    pub fn from_reader(read: &mut impl BufRead) -> Result<Self, io::Error> {
+        let mut magic = [0u8; 8];
+        read.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("File does not contain an IVF-PQ index."));
+        }
+        let params = IvfPqParams {
+            lists: read_u32(read)? as usize,
+            subspaces: read_u32(read)? as usize,
+            training_sample: read_u32(read)? as usize,
+            iterations: read_u32(read)? as usize,
+        };
+        if params.subspaces == 0 || !EMBEDDING_SIZE.is_multiple_of(params.subspaces) {
+            return Err(invalid_data(
+                "Number of subspaces of IVF-PQ index does not divide the embedding size.",
+            ));
+        }
+        let sub_dim = EMBEDDING_SIZE / params.subspaces;
+        let fingerprint = read_u64(read)?;
+        let centroids = read_f32s(read)?;
+        if !centroids.len().is_multiple_of(EMBEDDING_SIZE) {
+            return Err(invalid_data("IVF-PQ index contains a truncated centroid."));
+        }
+        let codebooks = (0..params.subspaces)
+            .map(|_| read_f32s(read))
+            .collect::<Result<Vec<_>, _>>()?;
+        if codebooks.iter().any(|codebook| {
+            !codebook.len().is_multiple_of(sub_dim) || codebook.len() / sub_dim > CODEBOOK_SIZE
+        }) {
+            return Err(invalid_data("IVF-PQ index contains an invalid codebook."));
+        }
+        let mut lists = Vec::with_capacity(centroids.len() / EMBEDDING_SIZE);
+        for _ in 0..centroids.len() / EMBEDDING_SIZE {
+            let num_nodes = read_u32(read)? as usize;
+            let nodes = (0..num_nodes)
+                .map(|_| read_u32(read))
+                .collect::<Result<Vec<_>, _>>()?;
+            // Read the codes piecewise, so a corrupt number of nodes can not allocate more memory
+            // than the file holds.
+            let mut codes = Vec::new();
+            read.by_ref()
+                .take((num_nodes * params.subspaces) as u64)
+                .read_to_end(&mut codes)?;
+            if codes.len() != num_nodes * params.subspaces {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let codes_valid = codes.chunks_exact(params.subspaces).all(|codes| {
+                codes
+                    .iter()
+                    .zip(&codebooks)
+                    .all(|(&code, codebook)| (code as usize) < codebook.len() / sub_dim)
+            });
+            if !codes_valid {
+                return Err(invalid_data(
+                    "IVF-PQ index refers to a codeword which does not exist.",
+                ));
+            }
+            lists.push(InvertedList { nodes, codes });
+        }
+        Ok(Self {
+            params,
+            nprobe: 8,
+            rerank: 0,
+            centroids,
+            codebooks,
+            lists,
+            fingerprint,
+        })
+    }
+}
+
+impl VectorIndex for IvfPq {
+    fn search(
+        &self,
+        embeddings: &Embeddings,
+        needle: &Embedding,
+        k: usize,
+    ) -> Result<Vec<(usize, f32)>, Error> {
+        if needle.is_degenerate() {
+            return Err(Error::DegenerateEmbedding);
+        }
+        let needle = needle.normalize();
+        let sub_dim = self.sub_dim();
+        // Similarity of each subspace of the needle with each codeword of that subspace. The
+        // similarity of the needle with an embedding is the similarity with its centroid plus the
+        // sum of these, picked by the codes of the embedding.
+        let lookup: Vec<Vec<f32>> = needle
+            .0
+            .chunks_exact(sub_dim)
+            .zip(&self.codebooks)
+            .map(|(sub_needle, codebook)| {
+                codebook
+                    .chunks_exact(sub_dim)
+                    .map(|codeword| dot(sub_needle, codeword))
+                    .collect()
+            })
+            .collect();
+        let probed = top_k(
+            self.centroids
+                .chunks_exact(EMBEDDING_SIZE)
+                .map(|centroid| dot(&needle.0, centroid))
+                .enumerate(),
+            self.nprobe,
+        );
+        let estimated = probed.into_iter().flat_map(|(list, centroid_similarity)| {
+            let list = &self.lists[list];
+            let lookup = &lookup;
+            list.nodes
+                .iter()
+                .zip(list.codes.chunks_exact(self.params.subspaces))
+                .map(move |(&node, codes)| {
+                    let residual_similarity: f32 = codes
+                        .iter()
+                        .zip(lookup)
+                        .map(|(&code, similarities)| similarities[code as usize])
+                        .sum();
+                    (node as usize, centroid_similarity + residual_similarity)
+                })
+        });
+        if self.rerank == 0 {
+            return Ok(top_k(estimated, k));
+        }
+        let candidates = top_k(estimated, self.rerank.max(k));
+        Ok(top_k(
+            candidates.into_iter().map(|(node, _)| {
+                let similarity = vector(embeddings, node as u32).dot(&needle);
+                (node, similarity)
+            }),
+            k,
+        ))
+    }
+}
+
+fn vector(embeddings: &Embeddings, node: u32) -> &Embedding {
+    &embeddings.records()[node as usize].embedding
+}
+
+/// Index of the centroid closest to `vector` and the difference between the two.
+fn residual(centroids: &[f32], vector: &[f32]) -> (usize, Vec<f32>) {
+    let list = nearest(centroids, EMBEDDING_SIZE, vector);
+    let centroid = &centroids[list * EMBEDDING_SIZE..(list + 1) * EMBEDDING_SIZE];
+    let residual = vector.iter().zip(centroid).map(|(v, c)| v - c).collect();
+    (list, residual)
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+fn write_f32s(write: &mut impl Write, values: &[f32]) -> Result<(), io::Error> {
+    write.write_all(&(values.len() as u64).to_le_bytes())?;
+    for value in values {
+        write.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_f32s(read: &mut impl BufRead) -> Result<Vec<f32>, io::Error> {
+    let len = read_u64(read)?;
+    (0..len).map(|_| read_f32(read)).collect()
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        index::recall_at_k,
+        test_util::{random_embedding, random_embeddings},
+        Record,
+    };
+
+    use super::*;
+
+    /// Smaller than the defaults, to keep the tests fast in debug builds
+    fn small_params() -> IvfPqParams {
+        IvfPqParams {
+            lists: 16,
+            subspaces: 16,
+            training_sample: 500,
+            iterations: 5,
+        }
+    }
+
+    #[test]
+    fn recall_with_reranking() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let embeddings = random_embeddings(&mut rng, 2_000);
+        let needles: Vec<_> = (0..20).map(|_| random_embedding(&mut rng)).collect();
+
+        let mut ivf_pq = IvfPq::build(&embeddings, small_params()).unwrap();
+        ivf_pq.set_nprobe(16);
+        ivf_pq.set_rerank(200);
+        let recall = recall_at_k(&ivf_pq, &embeddings, &needles, 10).unwrap();
+
+        assert!(recall > 0.9, "Recall is only {recall}");
+    }
+
+    #[test]
+    fn finds_itself_without_reranking() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let embeddings = random_embeddings(&mut rng, 500);
+
+        let ivf_pq = IvfPq::build(&embeddings, small_params()).unwrap();
+
+        let found = ivf_pq
+            .search(&embeddings, &embeddings.records()[123].embedding, 1)
+            .unwrap();
+        assert_eq!(123, found[0].0);
+    }
+
+    #[test]
+    fn skips_degenerate_embeddings() {
+        let mut embeddings = Embeddings::new();
+        embeddings.push(Record {
+            id: 1,
+            text_hash: 0,
+            embedding: Embedding([0.; EMBEDDING_SIZE]),
+        });
+        embeddings.push(Record {
+            id: 2,
+            text_hash: 0,
+            embedding: Embedding([1.; EMBEDDING_SIZE]),
+        });
+
+        let ivf_pq = IvfPq::build(&embeddings, small_params()).unwrap();
+        let found = ivf_pq
+            .search(&embeddings, &Embedding([1.; EMBEDDING_SIZE]), 2)
+            .unwrap();
+
+        assert_eq!(1, found.len());
+        assert_eq!(1, found[0].0);
+    }
+
+    #[test]
+    fn empty_embeddings() {
+        let embeddings = Embeddings::new();
+
+        let ivf_pq = IvfPq::build(&embeddings, small_params()).unwrap();
+        let found = ivf_pq
+            .search(&embeddings, &Embedding([1.; EMBEDDING_SIZE]), 1)
+            .unwrap();
+
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn ivf_pq_to_and_fro_bytes() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let embeddings = random_embeddings(&mut rng, 300);
+        let ivf_pq = IvfPq::build(&embeddings, small_params()).unwrap();
+
+        let mut bytes = Vec::new();
+        ivf_pq.write(&mut bytes).unwrap();
+        let read = IvfPq::from_reader(&mut Cursor::new(bytes)).unwrap();
+
+        assert_eq!(ivf_pq, read);
+        assert!(read.fits(&embeddings));
+    }
+
+    #[test]
+    fn outdated_once_same_texts_are_embedded_anew() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let embeddings = random_embeddings(&mut rng, 300);
+        let ivf_pq = IvfPq::build(&embeddings, small_params()).unwrap();
+
+        // Same posts and texts, e.g. embedded with a different representation
+        let embedded_anew = random_embeddings(&mut rng, 300);
+
+        assert!(!ivf_pq.fits(&embedded_anew));
+    }
+
+    #[test]
+    fn reject_invalid_params() {
+        let embeddings = random_embeddings(&mut StdRng::seed_from_u64(1), 10);
+        let no_lists = IvfPqParams {
+            lists: 0,
+            ..small_params()
+        };
+        let uneven_subspaces = IvfPqParams {
+            subspaces: 3,
+            ..small_params()
+        };
+
+        assert!(matches!(
+            IvfPq::build(&embeddings, no_lists),
+            Err(Error::InvalidIndexParams(_))
+        ));
+        assert!(matches!(
+            IvfPq::build(&embeddings, uneven_subspaces),
+            Err(Error::InvalidIndexParams(_))
+        ));
+    }
+
+    #[test]
+    fn refuse_nodes_beyond_embeddings() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let embeddings = random_embeddings(&mut rng, 100);
+        let mut ivf_pq = IvfPq::build(&embeddings, small_params()).unwrap();
+
+        // Corrupt, but still carrying the fingerprint of the embeddings
+        ivf_pq.lists[0].nodes.push(100);
+        ivf_pq.lists[0].codes.extend([0; 16]);
+
+        assert!(!ivf_pq.fits(&embeddings));
+    }
+}
@@ -0,0 +1,143 @@
+use rayon::prelude::*;
+
+/// Small, fast pseudo random number generator. Indices use it with fixed seeds, so building the
+/// same index twice yields the same result.
+pub(crate) struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in (0, 1]
+    pub fn next_f64(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in `0..n`. `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// `k` distinct indices out of `0..n`, drawn uniformly. All of them if `k >= n`.
+    pub fn sample(&mut self, n: usize, k: usize) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..n).collect();
+        let k = k.min(n);
+        // Partial Fisher-Yates shuffle
+        for i in 0..k {
+            let j = i + self.below(n - i);
+            indices.swap(i, j);
+        }
+        indices.truncate(k);
+        indices
+    }
+}
+
+/// Clusters `points` into (up to) `k` clusters using Lloyd's algorithm. `points` holds the points
+/// one after another, each with `dim` values. Returns the centroids in the same layout. There are
+/// fewer than `k` centroids only if there are fewer than `k` points.
+///
+/// Initial centroids are drawn from the points. Clusters which run empty are seeded again with a
+/// random point.
+pub(crate) fn kmeans(
+    points: &[f32],
+    dim: usize,
+    k: usize,
+    iterations: usize,
+    rng: &mut SplitMix64,
+) -> Vec<f32> {
+    let num_points = points.len() / dim;
+    let k = k.min(num_points);
+    let mut centroids: Vec<f32> = rng
+        .sample(num_points, k)
+        .into_iter()
+        .flat_map(|index| point(points, dim, index).iter().copied())
+        .collect();
+    let mut assignments = Vec::with_capacity(num_points);
+    for _ in 0..iterations {
+        points
+            .par_chunks_exact(dim)
+            .map(|point| nearest(&centroids, dim, point))
+            .collect_into_vec(&mut assignments);
+        let mut sums = vec![0f32; k * dim];
+        let mut counts = vec![0usize; k];
+        for (point, &cluster) in points.chunks_exact(dim).zip(&assignments) {
+            counts[cluster] += 1;
+            for (sum, value) in sums[cluster * dim..(cluster + 1) * dim]
+                .iter_mut()
+                .zip(point)
+            {
+                *sum += value;
+            }
+        }
+        for (cluster, centroid) in centroids.chunks_exact_mut(dim).enumerate() {
+            if counts[cluster] == 0 {
+                centroid.copy_from_slice(point(points, dim, rng.below(num_points)));
+                continue;
+            }
+            let sums = &sums[cluster * dim..(cluster + 1) * dim];
+            for (value, sum) in centroid.iter_mut().zip(sums) {
+                *value = sum / counts[cluster] as f32;
+            }
+        }
+    }
+    centroids
+}
+
+/// Index of the centroid with the smallest euclidean distance to `point`. `0` if there are no
+/// centroids.
+pub(crate) fn nearest(centroids: &[f32], dim: usize, point: &[f32]) -> usize {
+    centroids
+        .chunks_exact(dim)
+        .map(|centroid| squared_distance(centroid, point))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+        .unwrap_or(0)
+}
+
+fn point(points: &[f32], dim: usize, index: usize) -> &[f32] {
+    &points[index * dim..(index + 1) * dim]
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{kmeans, nearest, SplitMix64};
+
+    #[test]
+    fn separates_two_clusters() {
+        let points = [0., 0., 0.1, 0., 0., 0.1, 10., 10., 10.1, 10., 10., 10.1];
+        let mut rng = SplitMix64::new(1);
+
+        let centroids = kmeans(&points, 2, 2, 10, &mut rng);
+
+        let low = nearest(&centroids, 2, &[0., 0.]);
+        let high = nearest(&centroids, 2, &[10., 10.]);
+        assert_ne!(low, high);
+        assert!(centroids[low * 2] < 1. && centroids[high * 2] > 9.);
+    }
+
+    #[test]
+    fn fewer_points_than_clusters() {
+        let points = [1., 2., 3., 4.];
+        let mut rng = SplitMix64::new(1);
+
+        let centroids = kmeans(&points, 2, 5, 10, &mut rng);
+
+        assert_eq!(4, centroids.len());
+    }
+}
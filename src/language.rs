@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use whatlang::Lang;
+
+use crate::{index::read_u64, lexical::SourceStamp, strip_html, Document, Error, Post, PostReader};
+
+/// First bytes of every persisted set of detected languages.
+const MAGIC: &[u8; 8] = b"SSELANG1";
+
+/// Number of characters of a text looked at to detect its language. The start of a post tells its
+/// language just as well as the whole post, and long posts would slow down reading large dumps.
+const DETECTION_CHARS: usize = 1000;
+
+/// ISO 639-3 code of the language `text` is written in, e.g. `deu` for German or `por` for
+/// Portuguese. `None` if the text is too short or too ambiguous to tell reliably. Code snippets
+/// and technical terms make posts ambiguous more often than prose.
+pub fn detect_language(text: &str) -> Option<&'static str> {
+    let end = text
+        .char_indices()
+        .nth(DETECTION_CHARS)
+        .map_or(text.len(), |(end, _)| end);
+    let info = whatlang::detect(&text[..end])?;
+    info.is_reliable().then(|| info.lang().code())
+}
+
+/// Languages of the questions in a `Posts.xml`, detected from their titles and bodies. Separate
+/// from [`crate::read_documents`], because most searches do not need languages and detecting them
+/// in large dumps takes a while. Persisted next to the `Posts.xml`, so they are detected only once.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Languages {
+    /// ISO 639-3 code by post id. Questions whose language can not be told reliably are missing.
+    codes: HashMap<u64, &'static str>,
+    source: SourceStamp,
+}
+
+impl Languages {
+    /// Detects the language of every question in `posts_xml`.
+    pub fn detect(posts_xml: &Path) -> Result<Self, Error> {
+        let source =
+            SourceStamp::of(posts_xml).map_err(|cause| Error::ReadXmlFile(cause.into()))?;
+        let mut codes = HashMap::new();
+        let mut reader = PostReader::new(posts_xml)?;
+        while let Some(post) = reader.next_post()? {
+            if let Post::Question {
+                id, title, body, ..
+            } = post
+            {
+                // Short titles rarely tell their language reliably, so the body is part of the
+                // detected text, even if it is not embedded.
+                let text = format!("{title}\n\n{}", strip_html(&body));
+                if let Some(code) = detect_language(&text) {
+                    codes.insert(id, code);
+                }
+            }
+        }
+        Ok(Self { codes, source })
+    }
+
+    /// `true` if the languages have been detected in the current revision of `posts_xml`.
+    /// Otherwise they need to be detected anew.
+    pub fn fits(&self, posts_xml: &Path) -> bool {
+        SourceStamp::of(posts_xml).is_ok_and(|stamp| stamp == self.source)
+    }
+
+    /// ISO 639-3 code of the language of the question with the given post id.
+    pub fn get(&self, id: u64) -> Option<&'static str> {
+        self.codes.get(&id).copied()
+    }
+
+    /// Fills in the [`Document::language`] of each of the `documents`.
+    pub fn fill_in(&self, documents: &mut [Document]) {
+        for document in documents {
+            document.language = self.get(document.id).unwrap_or_default().to_owned();
+        }
+    }
+
+    pub fn write(&self, write: &mut impl Write) -> Result<(), io::Error> {
+        write.write_all(MAGIC)?;
+        write.write_all(&self.source.len.to_le_bytes())?;
+        write.write_all(&self.source.modified.to_le_bytes())?;
+        write.write_all(&(self.codes.len() as u64).to_le_bytes())?;
+        for (id, code) in &self.codes {
+            write.write_all(&id.to_le_bytes())?;
+            write.write_all(&[code.len() as u8])?;
+            write.write_all(code.as_bytes())?;
+        }
+        write.flush()
+    }
+
+    pub fn from_reader(read: &mut impl BufRead) -> Result<Self, io::Error> {
+        let mut magic = [0u8; 8];
+        read.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("File does not contain detected languages."));
+        }
+        let source = SourceStamp {
+            len: read_u64(read)?,
+            modified: read_u64(read)?,
+        };
+        let num_questions = read_u64(read)?;
+        let mut codes = HashMap::new();
+        for _ in 0..num_questions {
+            let id = read_u64(read)?;
+            let mut len = [0u8; 1];
+            read.read_exact(&mut len)?;
+            let mut code = vec![0u8; len[0] as usize];
+            read.read_exact(&mut code)?;
+            // Only codes of detectable languages are accepted, which also gives us their static
+            // representation.
+            let code = std::str::from_utf8(&code)
+                .ok()
+                .and_then(Lang::from_code)
+                .ok_or_else(|| invalid_data("Unknown language code."))?;
+            codes.insert(id, code.code());
+        }
+        Ok(Self { codes, source })
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// English name of the language with the ISO 639-3 `code`, e.g. `German` for `deu`. `None` for
+/// codes of languages which are not detected.
+pub fn language_name(code: &str) -> Option<&'static str> {
+    Lang::from_code(code).map(|lang| lang.eng_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{detect_language, language_name, Languages};
+    use crate::{read_documents, Chunking, Content};
+
+    #[test]
+    fn detect_languages_of_localized_communities() {
+        assert_eq!(
+            Some("deu"),
+            detect_language("Wie kann ich die Schichthöhe meines 3D-Druckers verringern?")
+        );
+        assert_eq!(
+            Some("por"),
+            detect_language("Como faço para converter uma string em um número inteiro em Java?")
+        );
+        assert_eq!(
+            Some("rus"),
+            detect_language("Как преобразовать строку в целое число в Java?")
+        );
+        assert_eq!(Some("German"), language_name("deu"));
+        assert_eq!(None, language_name("xx"));
+    }
+
+    #[test]
+    fn detect_languages_of_questions() {
+        let posts_xml = "./tests/small-posts.xml".as_ref();
+        let mut documents = read_documents(posts_xml, Content::Title, Chunking::whole()).unwrap();
+        assert!(documents
+            .iter()
+            .all(|document| document.language.is_empty()));
+
+        let languages = Languages::detect(posts_xml).unwrap();
+        languages.fill_in(&mut documents);
+
+        let health = documents.iter().find(|document| document.id == 2).unwrap();
+        assert_eq!("eng", health.language);
+        assert!(languages.fits(posts_xml));
+    }
+
+    #[test]
+    fn languages_to_and_fro_bytes() {
+        let languages = Languages::detect("./tests/small-posts.xml".as_ref()).unwrap();
+
+        let mut bytes = Vec::new();
+        languages.write(&mut bytes).unwrap();
+        let read = Languages::from_reader(&mut Cursor::new(bytes)).unwrap();
+
+        assert_eq!(languages, read);
+        assert_eq!(Some("eng"), read.get(2));
+    }
+}
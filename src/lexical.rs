@@ -0,0 +1,728 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, Read, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use rust_stemmers::{Algorithm, Stemmer};
+
+use crate::{
+    embedding::top_k,
+    index::{read_f32, read_u32, read_u64},
+    query::Query,
+    read_documents, Chunking, Content, Error,
+};
+
+/// Parameters of the BM25 ranking function.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bm25Params {
+    /// Controls how quickly repeated occurrences of a term saturate.
+    pub k1: f32,
+    /// Controls how strongly scores are normalized by document length. `0` disables the
+    /// normalization, `1` normalizes fully.
+    pub b: f32,
+}
+
+impl Default for Bm25Params {
+    fn default() -> Self {
+        Self { k1: 1.2, b: 0.75 }
+    }
+}
+
+/// First bytes of every persisted inverted index.
+const MAGIC: &[u8; 8] = b"SSEINVX1";
+/// Maximum number of documents or postings memory is reserved for, before reading them from a
+/// file.
+const MAX_PREALLOCATED: usize = 1 << 20;
+
+/// English words too common to tell posts apart. Sorted, so it can be searched with binary search.
+const STOPWORDS: &[&str] = &[
+    "a",
+    "about",
+    "above",
+    "after",
+    "again",
+    "against",
+    "all",
+    "am",
+    "an",
+    "and",
+    "any",
+    "are",
+    "as",
+    "at",
+    "be",
+    "because",
+    "been",
+    "before",
+    "being",
+    "below",
+    "between",
+    "both",
+    "but",
+    "by",
+    "could",
+    "did",
+    "do",
+    "does",
+    "doing",
+    "down",
+    "during",
+    "each",
+    "few",
+    "for",
+    "from",
+    "further",
+    "had",
+    "has",
+    "have",
+    "having",
+    "he",
+    "her",
+    "here",
+    "hers",
+    "herself",
+    "him",
+    "himself",
+    "his",
+    "how",
+    "i",
+    "if",
+    "in",
+    "into",
+    "is",
+    "it",
+    "its",
+    "itself",
+    "me",
+    "more",
+    "most",
+    "my",
+    "myself",
+    "nor",
+    "of",
+    "off",
+    "on",
+    "once",
+    "only",
+    "or",
+    "other",
+    "our",
+    "ours",
+    "ourselves",
+    "out",
+    "over",
+    "own",
+    "same",
+    "she",
+    "should",
+    "so",
+    "some",
+    "such",
+    "than",
+    "that",
+    "the",
+    "their",
+    "theirs",
+    "them",
+    "themselves",
+    "then",
+    "there",
+    "these",
+    "they",
+    "this",
+    "those",
+    "through",
+    "to",
+    "too",
+    "under",
+    "until",
+    "up",
+    "very",
+    "was",
+    "we",
+    "were",
+    "what",
+    "when",
+    "where",
+    "which",
+    "while",
+    "who",
+    "whom",
+    "why",
+    "will",
+    "with",
+    "would",
+    "you",
+    "your",
+    "yours",
+    "yourself",
+    "yourselves",
+];
+
+/// Splits a text into lowercase words. A word is a run of alphanumeric characters or underscores,
+/// so identifiers like `ERR_CONNECTION_REFUSED` or `G28` stay intact.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Terms of a text, together with the position of their word. Stopwords are dropped and the
+/// remaining words are reduced to their stem, so `printing` matches `printed`. Dropped words
+/// still take up a position, so phrases only match words which are actually adjacent.
+pub fn analyze(text: &str) -> impl Iterator<Item = (u32, String)> + '_ {
+    let stemmer = Stemmer::create(Algorithm::English);
+    tokenize(text)
+        .enumerate()
+        .filter(|(_, word)| STOPWORDS.binary_search(&word.as_str()).is_err())
+        .map(move |(position, word)| (position as u32, stemmer.stem(&word).into_owned()))
+}
+
+/// Occurrences of a term within one document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Posting {
+    document: u32,
+    /// Positions of the term within the document, ascending.
+    positions: Vec<u32>,
+}
+
+/// Identifies the revision of a `Posts.xml`, so an index built from an older one is not used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct SourceStamp {
+    pub(crate) len: u64,
+    /// Time of the last modification in nanoseconds since the unix epoch
+    pub(crate) modified: u64,
+}
+
+impl SourceStamp {
+    pub(crate) fn of(path: &Path) -> Result<Self, io::Error> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos() as u64)
+            .unwrap_or(0);
+        Ok(Self {
+            len: metadata.len(),
+            modified,
+        })
+    }
+}
+
+/// Positional inverted index over the titles and bodies of all questions. Answers keyword, phrase
+/// and boolean queries and ranks the results using BM25. Needs no embeddings, so it works without
+/// any API calls. Persisted next to the `Posts.xml` it has been built from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvertedIndex {
+    params: Bm25Params,
+    /// Post id of each document
+    ids: Vec<u64>,
+    /// Document of each post id, so titles are found without scanning `ids`.
+    documents: HashMap<u64, u32>,
+    /// Title of each document, so results can be shown without parsing the posts again.
+    titles: Vec<String>,
+    /// Number of words in each document
+    lengths: Vec<u32>,
+    average_length: f32,
+    /// Occurrences of each term, ordered by document
+    postings: HashMap<String, Vec<Posting>>,
+    source: SourceStamp,
+}
+
+impl InvertedIndex {
+    /// Indexes documents given as tuples of post id, title and text.
+    pub fn new<'a>(
+        documents: impl IntoIterator<Item = (u64, &'a str, &'a str)>,
+        params: Bm25Params,
+    ) -> Self {
+        let mut ids = Vec::new();
+        let mut titles = Vec::new();
+        let mut lengths = Vec::new();
+        let mut postings: HashMap<String, Vec<Posting>> = HashMap::new();
+        for (document, (id, title, text)) in documents.into_iter().enumerate() {
+            let mut positions: HashMap<String, Vec<u32>> = HashMap::new();
+            let mut length = 0;
+            for (position, term) in analyze(text) {
+                positions.entry(term).or_default().push(position);
+                length = position + 1;
+            }
+            for (term, positions) in positions {
+                postings.entry(term).or_default().push(Posting {
+                    document: document as u32,
+                    positions,
+                });
+            }
+            ids.push(id);
+            titles.push(title.to_owned());
+            lengths.push(length);
+        }
+        let total: u64 = lengths.iter().map(|&length| length as u64).sum();
+        let average_length = if lengths.is_empty() {
+            0.
+        } else {
+            total as f32 / lengths.len() as f32
+        };
+        Self {
+            params,
+            documents: documents_by_id(&ids),
+            ids,
+            titles,
+            lengths,
+            average_length,
+            postings,
+            source: SourceStamp::default(),
+        }
+    }
+
+    /// Indexes title and body of every question in a `Posts.xml`.
+    pub fn build(posts_xml: &Path, params: Bm25Params) -> Result<Self, Error> {
+        let source =
+            SourceStamp::of(posts_xml).map_err(|cause| Error::ReadXmlFile(cause.into()))?;
+        let documents = read_documents(posts_xml, Content::TitleAndBody, Chunking::whole())?;
+        let mut index = Self::new(
+            documents.iter().map(|document| {
+                (
+                    document.id,
+                    document.title.as_str(),
+                    document.chunks[0].as_str(),
+                )
+            }),
+            params,
+        );
+        index.source = source;
+        Ok(index)
+    }
+
+    /// `true` if the index has been built from the current revision of `posts_xml`. Otherwise
+    /// the index needs to be built anew.
+    pub fn fits(&self, posts_xml: &Path) -> bool {
+        SourceStamp::of(posts_xml).is_ok_and(|stamp| stamp == self.source)
+    }
+
+    /// Number of indexed documents
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Title of the question with the given post id.
+    pub fn title(&self, id: u64) -> Option<&str> {
+        let document = *self.documents.get(&id)?;
+        Some(&self.titles[document as usize])
+    }
+
+    /// The `k` documents matching `query` with the highest BM25 score, as tuples of post id and
+    /// score. Highest score first, ties ordered by id. Each term and phrase the query asks for
+    /// contributes to the score. Excluded terms do not.
+    pub fn search(&self, query: &Query, k: usize) -> Vec<(u64, f32)> {
+        let mut scores: HashMap<u32, f32> = self
+            .matches(query)
+            .into_iter()
+            .map(|document| (document, 0.))
+            .collect();
+        for leaf in query.positive_leaves() {
+            let frequencies = self.frequencies(leaf);
+            let idf = self.idf(frequencies.len());
+            for (document, frequency) in frequencies {
+                if let Some(score) = scores.get_mut(&document) {
+                    *score += idf * self.saturation(document, frequency);
+                }
+            }
+        }
+        top_k(
+            scores
+                .into_iter()
+                .map(|(document, score)| (self.ids[document as usize], score)),
+            k,
+        )
+    }
+
+    /// Documents matching the query, ascending.
+    fn matches(&self, query: &Query) -> Vec<u32> {
+        match query {
+            Query::Term(_) | Query::Phrase(_) => self
+                .frequencies(query)
+                .into_iter()
+                .map(|(document, _)| document)
+                .collect(),
+            Query::And(queries) => {
+                let (excluded, required): (Vec<_>, Vec<_>) = queries
+                    .iter()
+                    .partition(|query| matches!(query, Query::Not(_)));
+                let mut matching = match required.split_first() {
+                    None => (0..self.len() as u32).collect(),
+                    Some((first, rest)) => {
+                        rest.iter().fold(self.matches(first), |matching, query| {
+                            intersection(&matching, &self.matches(query))
+                        })
+                    }
+                };
+                for query in excluded {
+                    let Query::Not(query) = query else {
+                        unreachable!()
+                    };
+                    matching = difference(&matching, &self.matches(query));
+                }
+                matching
+            }
+            Query::Or(queries) => queries.iter().fold(Vec::new(), |matching, query| {
+                union(&matching, &self.matches(query))
+            }),
+            Query::Not(query) => {
+                let all: Vec<u32> = (0..self.len() as u32).collect();
+                difference(&all, &self.matches(query))
+            }
+        }
+    }
+
+    /// Documents containing a term or phrase, with the number of occurrences. Ordered by document.
+    /// Empty for any other query.
+    fn frequencies(&self, query: &Query) -> Vec<(u32, u32)> {
+        match query {
+            Query::Term(term) => self
+                .postings
+                .get(term)
+                .map(|postings| {
+                    postings
+                        .iter()
+                        .map(|posting| (posting.document, posting.positions.len() as u32))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            Query::Phrase(terms) => self.phrase_frequencies(terms),
+            _ => Vec::new(),
+        }
+    }
+
+    /// `terms` are tuples of the offset of each term within the phrase and the term. The first
+    /// offset is zero.
+    fn phrase_frequencies(&self, terms: &[(u32, String)]) -> Vec<(u32, u32)> {
+        let Some(postings) = terms
+            .iter()
+            .map(|(_, term)| self.postings.get(term))
+            .collect::<Option<Vec<_>>>()
+        else {
+            // At least one of the terms is not part of any document
+            return Vec::new();
+        };
+        let mut frequencies = Vec::new();
+        for first in postings[0] {
+            let others: Option<Vec<&Posting>> = postings[1..]
+                .iter()
+                .map(|postings| {
+                    postings
+                        .binary_search_by_key(&first.document, |posting| posting.document)
+                        .ok()
+                        .map(|index| &postings[index])
+                })
+                .collect();
+            let Some(others) = others else {
+                continue;
+            };
+            let occurrences = first
+                .positions
+                .iter()
+                .filter(|&&start| {
+                    others
+                        .iter()
+                        .zip(&terms[1..])
+                        .all(|(posting, (offset, _))| {
+                            posting.positions.binary_search(&(start + offset)).is_ok()
+                        })
+                })
+                .count();
+            if occurrences > 0 {
+                frequencies.push((first.document, occurrences as u32));
+            }
+        }
+        frequencies
+    }
+
+    /// Inverse document frequency of a term contained in `containing` documents. Rare terms weigh
+    /// more.
+    fn idf(&self, containing: usize) -> f32 {
+        let n = self.len() as f32;
+        let containing = containing as f32;
+        (1. + (n - containing + 0.5) / (containing + 0.5)).ln()
+    }
+
+    /// Term frequency component, normalized by the length of the document.
+    fn saturation(&self, document: u32, frequency: u32) -> f32 {
+        let Bm25Params { k1, b } = self.params;
+        let frequency = frequency as f32;
+        let relative_length = self.lengths[document as usize] as f32 / self.average_length;
+        frequency * (k1 + 1.) / (frequency + k1 * (1. - b + b * relative_length))
+    }
+
+    pub fn write(&self, write: &mut impl Write) -> Result<(), io::Error> {
+        write.write_all(MAGIC)?;
+        write.write_all(&self.source.len.to_le_bytes())?;
+        write.write_all(&self.source.modified.to_le_bytes())?;
+        write.write_all(&self.params.k1.to_le_bytes())?;
+        write.write_all(&self.params.b.to_le_bytes())?;
+        write.write_all(&(self.ids.len() as u64).to_le_bytes())?;
+        for ((id, title), length) in self.ids.iter().zip(&self.titles).zip(&self.lengths) {
+            write.write_all(&id.to_le_bytes())?;
+            write.write_all(&length.to_le_bytes())?;
+            write_str(write, title)?;
+        }
+        write.write_all(&(self.postings.len() as u64).to_le_bytes())?;
+        for (term, postings) in &self.postings {
+            write_str(write, term)?;
+            write.write_all(&(postings.len() as u32).to_le_bytes())?;
+            for posting in postings {
+                write.write_all(&posting.document.to_le_bytes())?;
+                write.write_all(&(posting.positions.len() as u32).to_le_bytes())?;
+                for position in &posting.positions {
+                    write.write_all(&position.to_le_bytes())?;
+                }
+            }
+        }
+        write.flush()
+    }
+
+    pub fn from_reader(read: &mut impl BufRead) -> Result<Self, io::Error> {
+        let mut magic = [0u8; 8];
+        read.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("File does not contain an inverted index."));
+        }
+        let source = SourceStamp {
+            len: read_u64(read)?,
+            modified: read_u64(read)?,
+        };
+        let params = Bm25Params {
+            k1: read_f32(read)?,
+            b: read_f32(read)?,
+        };
+        let num_documents = read_u64(read)? as usize;
+        // Counts are not trusted until the entries have actually been read, so a corrupt file can
+        // not make us allocate huge amounts of memory upfront.
+        let mut ids = Vec::with_capacity(num_documents.min(MAX_PREALLOCATED));
+        let mut titles = Vec::with_capacity(num_documents.min(MAX_PREALLOCATED));
+        let mut lengths = Vec::with_capacity(num_documents.min(MAX_PREALLOCATED));
+        for _ in 0..num_documents {
+            ids.push(read_u64(read)?);
+            lengths.push(read_u32(read)?);
+            titles.push(read_string(read)?);
+        }
+        let num_terms = read_u64(read)?;
+        let mut postings = HashMap::new();
+        for _ in 0..num_terms {
+            let term = read_string(read)?;
+            let num_postings = read_u32(read)?;
+            let mut term_postings =
+                Vec::with_capacity((num_postings as usize).min(MAX_PREALLOCATED));
+            for _ in 0..num_postings {
+                let document = read_u32(read)?;
+                if document as usize >= num_documents {
+                    return Err(invalid_data(
+                        "Inverted index refers to a document which does not exist.",
+                    ));
+                }
+                let num_positions = read_u32(read)?;
+                let positions = (0..num_positions)
+                    .map(|_| read_u32(read))
+                    .collect::<Result<_, _>>()?;
+                term_postings.push(Posting {
+                    document,
+                    positions,
+                });
+            }
+            postings.insert(term, term_postings);
+        }
+        let total: u64 = lengths.iter().map(|&length| length as u64).sum();
+        let average_length = if lengths.is_empty() {
+            0.
+        } else {
+            total as f32 / lengths.len() as f32
+        };
+        Ok(Self {
+            params,
+            documents: documents_by_id(&ids),
+            ids,
+            titles,
+            lengths,
+            average_length,
+            postings,
+            source,
+        })
+    }
+}
+
+/// Maps each post id to its document. The first document wins, if a post id occurs twice.
+fn documents_by_id(ids: &[u64]) -> HashMap<u64, u32> {
+    let mut documents = HashMap::with_capacity(ids.len());
+    for (document, &id) in ids.iter().enumerate() {
+        documents.entry(id).or_insert(document as u32);
+    }
+    documents
+}
+
+/// Elements contained in both sorted slices
+fn intersection(a: &[u32], b: &[u32]) -> Vec<u32> {
+    a.iter()
+        .copied()
+        .filter(|element| b.binary_search(element).is_ok())
+        .collect()
+}
+
+/// Elements of the sorted slice `a`, which are not contained in the sorted slice `b`
+fn difference(a: &[u32], b: &[u32]) -> Vec<u32> {
+    a.iter()
+        .copied()
+        .filter(|element| b.binary_search(element).is_err())
+        .collect()
+}
+
+/// Elements contained in either of the sorted slices, sorted
+fn union(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut union: Vec<u32> = a.iter().chain(b).copied().collect();
+    union.sort_unstable();
+    union.dedup();
+    union
+}
+
+fn write_str(write: &mut impl Write, text: &str) -> Result<(), io::Error> {
+    write.write_all(&(text.len() as u32).to_le_bytes())?;
+    write.write_all(text.as_bytes())
+}
+
+fn read_string(read: &mut impl BufRead) -> Result<String, io::Error> {
+    let len = read_u32(read)?;
+    let mut bytes = Vec::new();
+    read.by_ref().take(len.into()).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("Inverted index contains invalid UTF-8."))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::Query;
+
+    use super::{analyze, tokenize, Bm25Params, InvertedIndex, STOPWORDS};
+
+    fn index() -> InvertedIndex {
+        InvertedIndex::new(
+            [
+                (1, "Noise", "My printer makes noise while printing"),
+                (2, "M104", "Printer shows M104 error when heating the bed"),
+                (3, "Layers", "Minimum layer height of my resin printer"),
+                (4, "Height", "The height of the first layer"),
+            ],
+            Bm25Params::default(),
+        )
+    }
+
+    fn ids(found: Vec<(u64, f32)>) -> Vec<u64> {
+        let mut ids: Vec<_> = found.into_iter().map(|(id, _)| id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn keep_identifiers_intact() {
+        let words: Vec<_> = tokenize("Homing with G28 fails: ERR_ENDSTOP, 0x1F!").collect();
+
+        assert_eq!(
+            vec!["homing", "with", "g28", "fails", "err_endstop", "0x1f"],
+            words
+        );
+    }
+
+    #[test]
+    fn drop_stopwords_and_stem() {
+        let terms: Vec<_> = analyze("The printers are printing").collect();
+
+        assert_eq!(
+            vec![(1, "printer".to_owned()), (3, "print".to_owned())],
+            terms
+        );
+    }
+
+    #[test]
+    fn stopwords_are_sorted() {
+        assert!(STOPWORDS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn rare_terms_weigh_more() {
+        let found = index().search(&Query::any_of("printer M104"), 4);
+
+        assert_eq!(3, found.len());
+        assert_eq!(2, found[0].0);
+        assert!(found[0].1 > found[1].1);
+    }
+
+    #[test]
+    fn all_terms_are_required_by_default() {
+        let found = index().search(&Query::parse("printer height").unwrap(), 10);
+
+        assert_eq!(vec![3], ids(found));
+    }
+
+    #[test]
+    fn phrase_requires_adjacent_words() {
+        let found = index().search(&Query::parse("\"layer height\"").unwrap(), 10);
+
+        assert_eq!(vec![3], ids(found));
+    }
+
+    #[test]
+    fn exclude_terms() {
+        let found = index().search(&Query::parse("height AND -resin").unwrap(), 10);
+
+        assert_eq!(vec![4], ids(found));
+    }
+
+    #[test]
+    fn either_term() {
+        let found = index().search(&Query::parse("noise OR m104").unwrap(), 10);
+
+        assert_eq!(vec![1, 2], ids(found));
+    }
+
+    #[test]
+    fn title_by_post_id() {
+        let index = index();
+
+        assert_eq!(Some("M104"), index.title(2));
+        assert_eq!(None, index.title(5));
+    }
+
+    #[test]
+    fn inverted_index_to_and_fro_bytes() {
+        let index = index();
+
+        let mut bytes = Vec::new();
+        index.write(&mut bytes).unwrap();
+        let read = InvertedIndex::from_reader(&mut Cursor::new(bytes)).unwrap();
+
+        assert_eq!(index, read);
+        assert_eq!(Some("Layers"), read.title(3));
+    }
+
+    #[test]
+    fn reject_corrupt_counts() {
+        let mut bytes = Vec::new();
+        index().write(&mut bytes).unwrap();
+        // Number of documents, following magic bytes, source stamp and BM25 parameters
+        bytes[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        assert!(InvertedIndex::from_reader(&mut Cursor::new(bytes)).is_err());
+    }
+}
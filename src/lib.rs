@@ -1,9 +1,56 @@
+mod answer;
+mod boost;
+mod cache;
+mod document;
+mod duplicates;
 mod embedding;
 mod error;
+mod filter;
+mod hnsw;
+mod hybrid;
+mod index;
+mod ivf_pq;
+mod kmeans;
+mod language;
+mod lexical;
+mod mmr;
+mod page;
+mod quantization;
+mod query;
 mod reader;
+mod rerank;
+mod sites;
+#[cfg(test)]
+mod test_util;
+mod topics;
 
 pub use self::{
-    embedding::{Embedding, Embeddings},
+    answer::{read_answers, Answer},
+    boost::{BoostComponents, BoostWeights},
+    cache::{source_hash, CacheMetadata, EmbeddingCache},
+    document::{read_documents, strip_html, Chunking, Content, Document},
+    duplicates::{duplicate_clusters, duplicate_pairs},
+    embedding::{text_hash, Embedding, Embeddings, Record, Representation, EMBEDDING_SIZE, MODEL},
     error::Error,
+    filter::Filter,
+    hnsw::{Hnsw, HnswParams},
+    hybrid::Fusion,
+    index::{
+        recall_at_k, search_post_embeddings, search_post_embeddings_where, search_posts,
+        similar_posts, ExactSearch, VectorIndex,
+    },
+    ivf_pq::{IvfPq, IvfPqParams},
+    language::{detect_language, language_name, Languages},
+    lexical::{analyze, tokenize, Bm25Params, InvertedIndex},
+    mmr::Mmr,
+    page::{page, Cursor, Page, PageStart},
+    quantization::{
+        BinaryEmbedding, BinaryEmbeddings, Int8Embedding, Int8Embeddings, Quantized,
+        QuantizedEmbeddings,
+    },
+    query::Query,
     reader::{Post, PostReader},
+    rerank::{FinalOrder, FullEmbeddings, LexicalOverlap, Reranker, Scorer},
+    sites::{merge_sites, Normalization},
+    topics::{cluster_topics, Topic, TopicParams},
 };
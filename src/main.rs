@@ -1,13 +1,25 @@
 use std::{
-    fs::File,
-    io::{self, BufReader},
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use aleph_alpha_client::{Client, Prompt, SemanticRepresentation, TaskSemanticEmbedding};
+use aleph_alpha_client::Client;
 use anyhow::Error;
-use clap::Parser;
-use search_stack_exchange::{Embedding, Embeddings, Post, PostReader};
+use clap::{builder::RangedU64ValueParser, Parser, ValueEnum};
+use search_stack_exchange::{
+    cluster_topics, detect_language, duplicate_clusters, duplicate_pairs, language_name,
+    merge_sites, page, read_answers, read_documents, search_post_embeddings,
+    search_post_embeddings_where, search_posts, similar_posts, source_hash, text_hash, Bm25Params,
+    BoostComponents, BoostWeights, CacheMetadata, Chunking, Content, Cursor, Document, Embedding,
+    EmbeddingCache, Embeddings, Error as LibError, ExactSearch, Filter, FinalOrder, FullEmbeddings,
+    Fusion, Hnsw, HnswParams, InvertedIndex, IvfPq, IvfPqParams, Languages, LexicalOverlap, Mmr,
+    Normalization, PageStart, Query, Record, Representation, Reranker, TopicParams, VectorIndex,
+    EMBEDDING_SIZE, MODEL,
+};
+use serde_json::json;
 
 /// Semantic Search on top of stack overflow
 #[derive(Parser)]
@@ -19,23 +31,543 @@ struct Cli {
 
 #[derive(Parser)]
 enum Command {
-    /// The question with the title which fits your query best
+    /// The questions with the titles which fit your query best, together with their similarity
     Question {
         #[clap(flatten)]
         title_opt: TitleOpt,
+        /// Number of questions to show. Best match first.
+        #[clap(long = "top", short = 'k', default_value = "1")]
+        top: usize,
+        /// Only show questions with at least this score. In `semantic` mode the score is the
+        /// similarity to your query, which ranges from -1 to 1.
+        #[clap(long)]
+        min_score: Option<f32>,
+        #[clap(flatten)]
+        index_opt: IndexOpt,
+        #[clap(flatten)]
+        hybrid_opt: HybridOpt,
+        #[clap(flatten)]
+        rerank_opt: RerankOpt,
+        #[clap(flatten)]
+        diversity_opt: DiversityOpt,
+        #[clap(flatten)]
+        filter_opt: FilterOpt,
+        #[clap(flatten)]
+        boost_opt: BoostOpt,
+        #[clap(flatten)]
+        language_opt: LanguageOpt,
+        #[clap(flatten)]
+        page_opt: PageOpt,
+    },
+    /// The answer to the question which fits your query best. Prints the title and similarity of
+    /// the question, followed by its accepted answer, or else its highest-scored one, as plain
+    /// text.
+    Answer {
+        #[clap(flatten)]
+        title_opt: TitleOpt,
+        /// Show all answers to the question, accepted answer first, the others by score.
+        #[clap(long)]
+        all: bool,
+        #[clap(flatten)]
+        index_opt: IndexOpt,
     },
+    /// Clusters of questions whose embeddings are so similar, that they likely ask the same. Each
+    /// cluster is printed as lines of post id and title, followed by an empty line. Largest
+    /// cluster first.
+    Duplicates {
+        #[clap(flatten)]
+        embedding_opt: EmbeddingOpt,
+        /// Minimum similarity of two questions to count as duplicates.
+        #[clap(long, default_value = "0.95")]
+        threshold: f32,
+        /// Number of the most similar questions each question is compared with. Use an
+        /// approximate `--index` for large communities.
+        #[clap(long, default_value = "10")]
+        neighbours: usize,
+        #[clap(flatten)]
+        index_opt: IndexOpt,
+    },
+    /// Questions from several communities at once, together with their normalized score and the
+    /// name of their community.
+    Multi {
+        /// Posts.xml of a community, optionally named, e.g. `electronics=dumps/Posts.xml`. Without
+        /// a name the community is named after the directory containing the file. Repeat it for
+        /// each community.
+        #[clap(long = "posts", required = true, value_parser = parse_site)]
+        sites: Vec<Site>,
+        /// Your question you want to ask
+        question: String,
+        /// Only show questions of this community. Repeat it to allow several.
+        #[clap(long = "site")]
+        only: Vec<String>,
+        /// Number of questions to show. Best match first.
+        #[clap(long = "top", short = 'k', default_value = "10")]
+        top: usize,
+        /// How the similarities found in each community are made comparable. `raw` merges them as
+        /// they are. `min-max` scales them, so each community's best question scores 1.
+        /// `z-score` measures how much a question stands out in its community.
+        #[clap(long, value_enum, default_value = "z-score")]
+        normalize: NormalizationArg,
+        #[clap(flatten)]
+        model_opt: ModelOpt,
+        #[clap(flatten)]
+        index_opt: IndexOpt,
+        #[clap(flatten)]
+        filter_opt: FilterOpt,
+        #[clap(flatten)]
+        language_opt: LanguageOpt,
+    },
+    /// Questions similar to an existing question, together with their similarity. Uses the
+    /// cached embedding of the question, so no API call is needed once all questions are
+    /// embedded.
+    Similar {
+        #[clap(flatten)]
+        embedding_opt: EmbeddingOpt,
+        /// Post id of the question
+        id: u64,
+        /// Number of questions to show. Best match first.
+        #[clap(long = "top", short = 'k', default_value = "10")]
+        top: usize,
+        #[clap(flatten)]
+        index_opt: IndexOpt,
+    },
+    /// Clusters the questions into topics and prints them as JSON, largest first. Each topic lists
+    /// its most typical questions and its most frequent tags.
+    Cluster {
+        #[clap(flatten)]
+        embedding_opt: EmbeddingOpt,
+        /// Number of topics.
+        #[clap(
+            long,
+            default_value = "20",
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        topics: usize,
+        /// Number of questions closest to the center of each topic, which are shown.
+        #[clap(long, default_value = "5")]
+        titles: usize,
+        /// Number of the most frequent tags shown for each topic.
+        #[clap(long, default_value = "5")]
+        tags: usize,
+    },
+    /// Questions containing the keywords of your query, ranked by BM25. Needs no Aleph Alpha
+    /// token.
+    Search {
+        /// Input Posts.xml for the stack exchange community you want to search
+        posts_xml: PathBuf,
+        /// Keywords a question must contain. Put phrases in double quotes, exclude words with a
+        /// leading `-` and combine words with `AND`, `OR`, `NOT` and parentheses. E.g.:
+        /// `"layer height" AND -resin`
+        query: String,
+        /// Number of questions to show. Best match first.
+        #[clap(long = "top", short = 'k', default_value = "10")]
+        top: usize,
+    },
+}
+
+/// Number of posts taken from each ranking in hybrid search, before fusing them.
+const FUSION_DEPTH: usize = 100;
+
+/// Number of posts taken from each community by `multi`. Their scores are normalized together.
+const SITE_DEPTH: usize = 100;
+
+/// A community searched by `multi`.
+#[derive(Clone)]
+struct Site {
+    name: String,
+    posts_xml: PathBuf,
+}
+
+/// Parses `name=path` or `path`. Communities given by path only are named after the directory
+/// containing the file, e.g. `3dprinting` for `3dprinting/Posts.xml`.
+fn parse_site(arg: &str) -> Result<Site, String> {
+    if let Some((name, path)) = arg.split_once('=') {
+        return Ok(Site {
+            name: name.to_owned(),
+            posts_xml: path.into(),
+        });
+    }
+    let posts_xml = PathBuf::from(arg);
+    let name = posts_xml
+        .parent()
+        .and_then(Path::file_name)
+        .or(posts_xml.file_stem())
+        .ok_or_else(|| format!("Can not name the community of '{arg}'. Use name=path."))?
+        .to_string_lossy()
+        .into_owned();
+    Ok(Site { name, posts_xml })
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NormalizationArg {
+    Raw,
+    MinMax,
+    ZScore,
+}
+
+impl From<NormalizationArg> for Normalization {
+    fn from(source: NormalizationArg) -> Self {
+        match source {
+            NormalizationArg::Raw => Normalization::Raw,
+            NormalizationArg::MinMax => Normalization::MinMax,
+            NormalizationArg::ZScore => Normalization::ZScore,
+        }
+    }
+}
+
+/// Number of the best posts which are reordered by community signals.
+const BOOST_DEPTH: usize = 100;
+
+#[derive(Parser)]
+struct BoostOpt {
+    /// Blend the score of each question with its votes, views, answers, accepted answer and age.
+    /// Popular questions with an accepted answer are usually what you are looking for.
+    #[clap(long)]
+    boost: bool,
+    /// JSON file with the weights of the signals blended by `--boost`, e.g.
+    /// `{"similarity": 1, "score": 0.2, "views": 0.05, "answers": 0.05, "accepted": 0.1,
+    /// "recency": 0.1, "half_life_days": 365}`. Missing weights keep their default. Implies
+    /// `--boost`.
+    #[clap(long)]
+    boost_config: Option<PathBuf>,
+    /// Show how much each signal contributes to the score of a boosted question.
+    #[clap(long)]
+    explain: bool,
+}
+
+impl BoostOpt {
+    /// Weights to boost questions with. `None` if questions are not boosted.
+    fn weights(&self) -> Result<Option<BoostWeights>, Error> {
+        match &self.boost_config {
+            Some(path) => Ok(Some(serde_json::from_reader(BufReader::new(File::open(
+                path,
+            )?))?)),
+            None => Ok(self.boost.then(BoostWeights::default)),
+        }
+    }
+}
+
+#[derive(Parser)]
+struct HybridOpt {
+    /// How questions are matched with your query. `semantic` compares embeddings. `lexical` ranks
+    /// titles and bodies by shared words using BM25, which finds exact error codes, part names or
+    /// commands. `hybrid` fuses both rankings.
+    #[clap(long, value_enum, default_value = "semantic")]
+    mode: SearchMode,
+    /// How `hybrid` mode fuses the rankings. `rrf` only looks at the rank of each question.
+    /// `weighted` adds up the scores of both searches, scaled to the range from 0 to 1.
+    #[clap(long, value_enum, default_value = "rrf")]
+    fusion: FusionKind,
+    /// Weight of the lexical score for `--fusion weighted`. The semantic score is weighted with
+    /// one minus this weight.
+    #[clap(long, default_value = "0.5")]
+    lexical_weight: f32,
+}
+
+impl HybridOpt {
+    fn fusion(&self) -> Fusion {
+        match self.fusion {
+            FusionKind::Rrf => Fusion::default(),
+            FusionKind::Weighted => Fusion::Weighted {
+                lexical_weight: self.lexical_weight,
+            },
+        }
+    }
+}
+
+#[derive(Parser)]
+struct FilterOpt {
+    /// Only show questions with this tag. Repeat it to require several tags.
+    #[clap(long = "tag")]
+    tags: Vec<String>,
+    /// Only show questions with at least this score, i.e. upvotes minus downvotes. Unlike
+    /// `--min-score`, which applies to the similarity to your query.
+    #[clap(long)]
+    min_post_score: Option<i64>,
+    /// Only show questions with an accepted answer.
+    #[clap(long)]
+    answered: bool,
+    /// Only show questions asked on or after this date, e.g. `2020` or `2020-06-01`.
+    #[clap(long)]
+    since: Option<String>,
+    /// Only show questions written in this language, given as ISO 639-3 code, e.g. `deu` for
+    /// German or `por` for Portuguese. Repeat it to allow several languages.
+    #[clap(long = "language")]
+    languages: Vec<String>,
+}
+
+impl FilterOpt {
+    /// All conditions combined. `None` if there are none.
+    fn filter(&self) -> Result<Option<Filter>, LibError> {
+        let mut filters: Vec<Filter> = self.tags.iter().cloned().map(Filter::Tag).collect();
+        filters.extend(self.min_post_score.map(Filter::MinScore));
+        if self.answered {
+            filters.push(Filter::Answered);
+        }
+        if let Some(since) = &self.since {
+            filters.push(Filter::since(since)?);
+        }
+        if !self.languages.is_empty() {
+            let languages = self
+                .languages
+                .iter()
+                .map(|code| Filter::language(code))
+                .collect::<Result<_, _>>()?;
+            filters.push(Filter::Or(languages));
+        }
+        Ok((!filters.is_empty()).then_some(Filter::And(filters)))
+    }
+
+    /// Whether the filter looks at the language of questions, which needs to be detected first.
+    fn uses_language(&self) -> bool {
+        !self.languages.is_empty()
+    }
+}
+
+#[derive(Parser)]
+struct PageOpt {
+    /// Skip this many questions, e.g. `--offset 10 --top 10` shows the second page of ten.
+    #[clap(long, conflicts_with = "cursor")]
+    offset: Option<usize>,
+    /// Continue after the last question of a previous page. If there are more questions, the
+    /// cursor to the next page is printed to standard error. Unlike `--offset`, questions stay on
+    /// their page, even if others have been added or removed in between.
+    #[clap(long)]
+    cursor: Option<String>,
+}
+
+impl PageOpt {
+    fn start(&self) -> Result<PageStart, LibError> {
+        match &self.cursor {
+            Some(cursor) => Ok(PageStart::After(Cursor::decode(cursor)?)),
+            None => Ok(PageStart::Offset(self.offset.unwrap_or(0))),
+        }
+    }
+}
+
+#[derive(Parser)]
+struct LanguageOpt {
+    /// Show the language of your question and of each question found. The embedding model is
+    /// multilingual, so semantic search finds questions in other languages than your own, e.g.
+    /// German questions on the Portuguese Stack Overflow. Lexical search only matches words
+    /// shared by query and question.
+    #[clap(long)]
+    show_language: bool,
+}
+
+impl LanguageOpt {
+    /// Reports the detected language of `question` on standard error, if languages are shown.
+    fn report_query_language(&self, question: &str) {
+        if self.show_language {
+            let name = detect_language(question).and_then(language_name);
+            eprintln!(
+                "Your question is in {}",
+                name.unwrap_or("an unknown language")
+            );
+        }
+    }
+}
+
+/// Column with the ISO 639-3 code of the language of a question, or `???` if it is unknown.
+fn language_column(language: &str) -> &str {
+    if language.is_empty() {
+        "???"
+    } else {
+        language
+    }
+}
+
+#[derive(Parser)]
+struct DiversityOpt {
+    /// Prefer questions which differ from the ones shown before them, so near-duplicates do not
+    /// crowd out other topics. Ranges from 0 to 1. `1` ranks by similarity to your query alone,
+    /// lower values trade similarity for diversity. Applies to the semantic search.
+    #[clap(long)]
+    diversity: Option<f32>,
+    /// Number of the most similar questions which `--diversity` selects from.
+    #[clap(long, default_value = "50")]
+    diversity_candidates: usize,
+}
+
+#[derive(Parser)]
+struct RerankOpt {
+    /// Scores the best questions anew with a more precise, but more expensive signal.
+    /// `full-embedding` compares embeddings with all dimensions of the model, which takes an API
+    /// call per question. `lexical` counts the words of your query contained in a question.
+    #[clap(long, value_enum)]
+    rerank_with: Option<RerankSignal>,
+    /// Number of questions found by the search which are reranked. Raised to the number of
+    /// questions shown, including those on previous pages, so every question shown is reranked.
+    #[clap(long, default_value = "20")]
+    rerank_depth: usize,
+    /// Order reranked questions by the weighted sum of both scores, each scaled to the range from
+    /// 0 to 1, rather than by the reranking score alone. The search score is weighted with one
+    /// minus this weight.
+    #[clap(long)]
+    rerank_weight: Option<f32>,
+}
+
+impl RerankOpt {
+    fn order(&self) -> FinalOrder {
+        match self.rerank_weight {
+            None => FinalOrder::Rerank,
+            Some(rerank_weight) => FinalOrder::Blend { rerank_weight },
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RerankSignal {
+    FullEmbedding,
+    Lexical,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SearchMode {
+    Semantic,
+    Lexical,
+    Hybrid,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FusionKind {
+    Rrf,
+    Weighted,
 }
 
 #[derive(Parser)]
 struct TitleOpt {
-    /// Input Posts.xml for the stack exchange community you want to search
-    posts_xml: PathBuf,
+    #[clap(flatten)]
+    embedding_opt: EmbeddingOpt,
     /// Your question you want to ask
     question: String,
+}
+
+#[derive(Parser)]
+struct EmbeddingOpt {
+    /// Input Posts.xml for the stack exchange community you want to search
+    posts_xml: PathBuf,
+    #[clap(flatten)]
+    model_opt: ModelOpt,
+}
+
+/// How questions are embedded. Shared by all communities searched.
+#[derive(Parser)]
+struct ModelOpt {
     /// Token for the Aleph Alpha API. You can see your token if you go to your profile at
     /// <https://app.aleph-alpha.com>.
     #[clap(long, short = 't', env = "AA_API_TOKEN", hide_env_values = true)]
     token: String,
+    /// How the titles are represented by the embedding model. Use `document` for asymmetric
+    /// search, which compares short queries with stored documents. Changing it replaces the
+    /// embeddings in the `.emb` file, so all titles are embedded anew.
+    #[clap(long, value_enum, default_value = "symmetric")]
+    document_representation: RepresentationArg,
+    /// How your question is represented by the embedding model. Defaults to `query` for
+    /// `document` titles and to `symmetric` for `symmetric` titles.
+    #[clap(long, value_enum)]
+    query_representation: Option<RepresentationArg>,
+    /// Which parts of each question are embedded. Questions with vague titles are easier to find,
+    /// if their body is embedded, too. Changing it embeds the questions anew.
+    #[clap(long, value_enum, default_value = "title")]
+    content: ContentArg,
+    /// Long texts are split into chunks of at most this many words. Each chunk is embedded
+    /// separately and a question is as similar to your query as its most similar chunk.
+    #[clap(long, default_value = "200")]
+    chunk_words: usize,
+    /// Number of words repeated at the start of the next chunk.
+    #[clap(long, default_value = "40")]
+    chunk_overlap: usize,
+}
+
+impl ModelOpt {
+    /// Representations of the questions and of the query, in this order.
+    fn representations(&self) -> (Representation, Representation) {
+        let document = Representation::from(self.document_representation);
+        let query = self
+            .query_representation
+            .map(Representation::from)
+            .unwrap_or(document.query());
+        (document, query)
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ContentArg {
+    /// Title of the question
+    Title,
+    /// Title and body of the question
+    Body,
+    /// Title and body of the question, and its accepted answer
+    Answer,
+}
+
+impl From<ContentArg> for Content {
+    fn from(source: ContentArg) -> Self {
+        match source {
+            ContentArg::Title => Content::Title,
+            ContentArg::Body => Content::TitleAndBody,
+            ContentArg::Answer => Content::TitleBodyAndAnswer,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RepresentationArg {
+    Symmetric,
+    Document,
+    Query,
+}
+
+impl From<RepresentationArg> for Representation {
+    fn from(source: RepresentationArg) -> Self {
+        match source {
+            RepresentationArg::Symmetric => Representation::Symmetric,
+            RepresentationArg::Document => Representation::Document,
+            RepresentationArg::Query => Representation::Query,
+        }
+    }
+}
+
+#[derive(Parser)]
+struct IndexOpt {
+    /// How to search the embeddings. `exact` compares your query with every question. `hnsw` walks
+    /// a graph, which is stored next to the embeddings. It is much faster for large communities,
+    /// but may miss some matches. `ivf-pq` only scans compressed embeddings of questions close to
+    /// your query. It needs much less memory than `hnsw`, but may miss more matches.
+    #[clap(long, value_enum, default_value = "exact")]
+    index: IndexKind,
+    /// Number of candidates the `hnsw` index considers during a search. Higher values miss fewer
+    /// matches, but take longer.
+    #[clap(long, default_value = "64")]
+    ef: usize,
+    /// Maximum number of neighbours of each question in the `hnsw` graph. Higher values miss fewer
+    /// matches, but take more memory. Changing it builds the graph anew.
+    #[clap(long = "hnsw-m", default_value = "16")]
+    hnsw_m: usize,
+    /// Number of lists the `ivf-pq` index scans during a search. Higher values miss fewer
+    /// matches, but take longer.
+    #[clap(long, default_value = "8")]
+    nprobe: usize,
+    /// Number of candidates of the `ivf-pq` index which are compared with your query again using
+    /// the uncompressed embeddings. `0` shows estimated similarities only.
+    #[clap(long, default_value = "100")]
+    rerank: usize,
+    /// Number of lists the questions are distributed to by the `ivf-pq` index. Changing it builds
+    /// the index anew.
+    #[clap(
+        long = "ivf-lists",
+        default_value = "1024",
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    ivf_lists: usize,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum IndexKind {
+    Exact,
+    Hnsw,
+    IvfPq,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -43,76 +575,653 @@ async fn main() -> Result<(), Error> {
     let opt = Cli::parse();
 
     match opt.command {
-        Command::Question { title_opt } => {
-            let TitleOpt {
-                posts_xml,
-                question,
-                token,
-            } = title_opt;
-
-            let client = Client::new(&token)?;
-            let titles = extract_titles(&posts_xml)?;
-
-            // Load embeddings if already calculated.
-            let mut embedding_path = posts_xml.to_owned();
-            embedding_path.set_extension("emb");
-            let embedding_cache = open_embedddings_cache(&embedding_path)?;
-            let title_embeddings = if let Some(cache) = embedding_cache {
-                eprintln!("Use cached embeddings");
-                Embeddings::from_reader_n(&mut BufReader::new(cache), titles.len())?
+        Command::Question {
+            title_opt,
+            top,
+            min_score,
+            index_opt,
+            hybrid_opt,
+            rerank_opt,
+            diversity_opt,
+            filter_opt,
+            boost_opt,
+            language_opt,
+            page_opt,
+        } => {
+            let start = page_opt.start()?;
+            let posts_xml = title_opt.embedding_opt.posts_xml.clone();
+            let filter = filter_opt.filter()?;
+            let boost = boost_opt.weights()?;
+            let languages = language_opt.show_language || filter_opt.uses_language();
+            // Metadata of each question, if needed by filters, boosting or to show languages
+            let metadata: HashMap<u64, Document> =
+                if filter.is_some() || boost.is_some() || languages {
+                    let mut questions =
+                        read_documents(&posts_xml, Content::Title, Chunking::whole())?;
+                    if languages {
+                        open_languages(&posts_xml)?.fill_in(&mut questions);
+                    }
+                    questions
+                        .into_iter()
+                        .map(|question| (question.id, question))
+                        .collect()
+                } else {
+                    HashMap::new()
+                };
+            // Ids of the questions matching the filter. `None` keeps all questions.
+            let allowed: Option<HashSet<u64>> = filter.map(|filter| {
+                metadata
+                    .values()
+                    .filter(|question| filter.matches(question))
+                    .map(|question| question.id)
+                    .collect()
+            });
+            let question = title_opt.question.clone();
+            language_opt.report_query_language(&question);
+            let token = title_opt.embedding_opt.model_opt.token.clone();
+            let (document_representation, query_representation) =
+                title_opt.embedding_opt.model_opt.representations();
+            // Questions on previous pages, this page and the one telling whether there is a next
+            // page.
+            let page_depth = start.skipped().saturating_add(top).saturating_add(1);
+            // Every question shown needs a reranked score, or scores of both stages would be
+            // mixed in a single ranking.
+            let rerank_depth = rerank_opt.rerank_depth.max(page_depth);
+            // Reranking and boosting need more candidates than are shown in the end
+            let mut top_candidates = page_depth;
+            if rerank_opt.rerank_with.is_some() {
+                top_candidates = top_candidates.max(rerank_depth);
+            }
+            if boost.is_some() {
+                top_candidates = top_candidates.max(BOOST_DEPTH);
+            }
+            // Hybrid search fuses longer rankings, so posts ranked low by one search, but high by
+            // the other, still make it to the top.
+            let depth = if hybrid_opt.mode == SearchMode::Hybrid {
+                top_candidates.max(FUSION_DEPTH)
+            } else {
+                top_candidates
+            };
+            let mut titles = HashMap::new();
+            // Texts the reranker scores. The first chunk of each question, or its title if it has
+            // only been found by lexical search.
+            let mut texts = HashMap::new();
+
+            let semantic = if hybrid_opt.mode == SearchMode::Lexical {
+                None
+            } else {
+                let (ranking, questions) = semantic_search(
+                    title_opt,
+                    &index_opt,
+                    &diversity_opt,
+                    allowed.as_ref(),
+                    depth,
+                )
+                .await?;
+                for question in questions {
+                    let mut chunks = question.chunks;
+                    texts.insert(question.id, chunks.swap_remove(0));
+                    titles.insert(question.id, question.title);
+                }
+                Some(ranking)
+            };
+            let lexical = if hybrid_opt.mode == SearchMode::Semantic {
+                None
             } else {
-                eprintln!("Generate embeddings");
-                // Generate embeddings
-                let embeddings =
-                    Embeddings::from_texts(&client, titles.iter().map(|s| s.as_str())).await?;
-                // Save them for the next time
-                let mut file = File::create(embedding_path)?;
-                embeddings.write(&mut file)?;
-                embeddings
+                let index = open_inverted_index(&posts_xml)?;
+                let query = Query::any_of(&question);
+                let ranking = match &allowed {
+                    None => index.search(&query, depth),
+                    Some(allowed) => {
+                        let mut ranking = index.search(&query, index.len());
+                        ranking.retain(|(id, _)| allowed.contains(id));
+                        ranking.truncate(depth);
+                        ranking
+                    }
+                };
+                for &(id, _) in &ranking {
+                    let title = index.title(id).expect("Every match belongs to a question");
+                    titles.insert(id, title.to_owned());
+                    texts.entry(id).or_insert_with(|| title.to_owned());
+                }
+                Some(ranking)
+            };
+            let mut matches = match (lexical, semantic) {
+                (Some(lexical), Some(semantic)) => {
+                    hybrid_opt
+                        .fusion()
+                        .fuse(&lexical, &semantic, top_candidates)
+                }
+                (Some(ranking), None) | (None, Some(ranking)) => ranking,
+                (None, None) => unreachable!("Every search mode uses at least one ranking"),
+            };
+            let text = |id| texts.get(&id).expect("Every match has a text").as_str();
+            matches = match rerank_opt.rerank_with {
+                None => matches,
+                Some(RerankSignal::Lexical) => {
+                    let reranker = Reranker::new(LexicalOverlap, rerank_depth, rerank_opt.order());
+                    reranker
+                        .rerank(&question, &matches, text, top_candidates)
+                        .await?
+                }
+                Some(RerankSignal::FullEmbedding) => {
+                    let client = Client::new(&token)?;
+                    let scorer =
+                        FullEmbeddings::new(&client, document_representation, query_representation);
+                    let reranker = Reranker::new(scorer, rerank_depth, rerank_opt.order());
+                    reranker
+                        .rerank(&question, &matches, text, top_candidates)
+                        .await?
+                }
             };
+            // Tuples of post id, score and the contribution of each signal, if boosted
+            let mut matches: Vec<(u64, f32, Option<BoostComponents>)> = match boost {
+                None => matches
+                    .into_iter()
+                    .map(|(id, score)| (id, score, None))
+                    .collect(),
+                Some(weights) => {
+                    let document = |id| metadata.get(&id).expect("Every match has metadata");
+                    weights
+                        .rank(&matches, document, today())
+                        .into_iter()
+                        .map(|(id, components)| (id, components.total(), Some(components)))
+                        .collect()
+                }
+            };
+            if let Some(min_score) = min_score {
+                matches.retain(|&(_, score, _)| score >= min_score);
+            }
+            if matches.is_empty() {
+                eprintln!("There are no questions matching your query.");
+            }
+            let ranking: Vec<(u64, f32)> =
+                matches.iter().map(|&(id, score, _)| (id, score)).collect();
+            let components: HashMap<u64, BoostComponents> = matches
+                .iter()
+                .filter_map(|&(id, _, components)| Some((id, components?)))
+                .collect();
+            let shown = page(&ranking, start, top);
 
-            let embed_question = TaskSemanticEmbedding {
-                prompt: Prompt::from_text(&question),
-                representation: SemanticRepresentation::Symmetric,
-                compress_to_size: Some(128),
+            for (id, score) in shown.results {
+                let title = titles.get(&id).expect("Every match belongs to a question");
+                if language_opt.show_language {
+                    let language = &metadata
+                        .get(&id)
+                        .expect("Every match has metadata")
+                        .language;
+                    println!("{score:.3}\t{}\t{title}", language_column(language));
+                } else {
+                    println!("{score:.3}\t{title}");
+                }
+                if let Some(c) = components.get(&id).filter(|_| boost_opt.explain) {
+                    println!(
+                        "\tsimilarity {:.3}, score {:.3}, views {:.3}, answers {:.3}, accepted \
+                        {:.3}, recency {:.3}",
+                        c.similarity, c.score, c.views, c.answers, c.accepted, c.recency
+                    );
+                }
+            }
+            if let Some(next) = shown.next {
+                eprintln!("Next page: --cursor {}", next.encode());
+            }
+        }
+        Command::Answer {
+            title_opt,
+            all,
+            index_opt,
+        } => {
+            let posts_xml = title_opt.embedding_opt.posts_xml.clone();
+            // Diversity does not change which question is the best one
+            let diversity_opt = DiversityOpt {
+                diversity: None,
+                diversity_candidates: 0,
             };
-            let question_embedding = &client
-                .semantic_embedding(&embed_question, &Default::default())
-                .await
-                .unwrap()
-                .embedding;
+            let (ranking, questions) =
+                semantic_search(title_opt, &index_opt, &diversity_opt, None, 1).await?;
+            if let Some(&(id, score)) = ranking.first() {
+                let question = questions
+                    .iter()
+                    .find(|question| question.id == id)
+                    .expect("Every match belongs to a question");
+                println!("{score:.3}\t{}", question.title);
+                let answers = read_answers(&posts_xml, id)?;
+                if answers.is_empty() {
+                    eprintln!("The question has not been answered yet.");
+                }
+                let shown = if all { answers.len() } else { 1 };
+                for answer in answers.iter().take(shown) {
+                    let accepted = if answer.accepted { ", accepted" } else { "" };
+                    println!(
+                        "\nAnswer with score {}{accepted}\n{}",
+                        answer.score, answer.text
+                    );
+                }
+            } else {
+                eprintln!("There are no questions matching your query.");
+            }
+        }
+        Command::Duplicates {
+            embedding_opt,
+            threshold,
+            neighbours,
+            index_opt,
+        } => {
+            let client = Client::new(&embedding_opt.model_opt.token)?;
+            let (embeddings, questions) =
+                embed_questions(&embedding_opt.posts_xml, &embedding_opt.model_opt, &client)
+                    .await?;
+            let index = open_index(&index_opt, &embedding_opt.posts_xml, &embeddings)?;
+            let pairs = duplicate_pairs(index.as_ref(), &embeddings, threshold, neighbours)?;
+            let clusters = duplicate_clusters(&pairs);
+            if clusters.is_empty() {
+                eprintln!("There are no duplicate questions.");
+            }
+            let titles: HashMap<_, _> = questions.into_iter().map(|q| (q.id, q.title)).collect();
+            for cluster in clusters {
+                for id in cluster {
+                    let title = titles
+                        .get(&id)
+                        .expect("Every embedding belongs to a question");
+                    println!("{id}\t{title}");
+                }
+                println!();
+            }
+        }
+        Command::Multi {
+            sites,
+            question,
+            only,
+            top,
+            normalize,
+            model_opt,
+            index_opt,
+            filter_opt,
+            language_opt,
+        } => {
+            let filter = filter_opt.filter()?;
+            let languages = language_opt.show_language || filter_opt.uses_language();
+            if let Some(unknown) = only
+                .iter()
+                .find(|name| sites.iter().all(|s| &s.name != *name))
+            {
+                anyhow::bail!("There is no community named '{unknown}'");
+            }
+            let sites: Vec<Site> = sites
+                .into_iter()
+                .filter(|site| only.is_empty() || only.contains(&site.name))
+                .collect();
+            let client = Client::new(&model_opt.token)?;
+            let (_, query_representation) = model_opt.representations();
+            language_opt.report_query_language(&question);
+            let question_embedding =
+                Embedding::from_text(&client, &question, query_representation).await?;
+            let depth = top.max(SITE_DEPTH);
+            let mut rankings = Vec::new();
+            let mut questions_by_site = Vec::new();
+            for site in &sites {
+                eprintln!("Search {}", site.name);
+                let (embeddings, mut questions) =
+                    embed_questions(&site.posts_xml, &model_opt, &client).await?;
+                if languages {
+                    open_languages(&site.posts_xml)?.fill_in(&mut questions);
+                }
+                let index = open_index(&index_opt, &site.posts_xml, &embeddings)?;
+                let site_questions: HashMap<_, _> =
+                    questions.into_iter().map(|q| (q.id, q)).collect();
+                let ranking = match &filter {
+                    None => search_posts(index.as_ref(), &embeddings, &question_embedding, depth)?,
+                    Some(filter) => search_post_embeddings_where(
+                        index.as_ref(),
+                        &embeddings,
+                        &question_embedding,
+                        depth,
+                        |id| site_questions.get(&id).is_some_and(|q| filter.matches(q)),
+                    )?
+                    .into_iter()
+                    .map(|(index, score)| (embeddings.id(index), score))
+                    .collect(),
+                };
+                rankings.push(ranking);
+                questions_by_site.push(site_questions);
+            }
+            let matches = merge_sites(&rankings, normalize.into(), top);
+            if matches.is_empty() {
+                eprintln!("There are no questions matching your query.");
+            }
+            for (site, id, score) in matches {
+                let question = questions_by_site[site]
+                    .get(&id)
+                    .expect("Every match belongs to a question");
+                let name = &sites[site].name;
+                if language_opt.show_language {
+                    let language = language_column(&question.language);
+                    println!("{score:.3}\t{name}\t{language}\t{}", question.title);
+                } else {
+                    println!("{score:.3}\t{name}\t{}", question.title);
+                }
+            }
+        }
+        Command::Similar {
+            embedding_opt,
+            id,
+            top,
+            index_opt,
+        } => {
+            let client = Client::new(&embedding_opt.model_opt.token)?;
+            let (embeddings, questions) =
+                embed_questions(&embedding_opt.posts_xml, &embedding_opt.model_opt, &client)
+                    .await?;
+            let index = open_index(&index_opt, &embedding_opt.posts_xml, &embeddings)?;
+            let matches = similar_posts(index.as_ref(), &embeddings, id, top)?;
+            if matches.is_empty() {
+                eprintln!("There are no other questions.");
+            }
+            let titles: HashMap<_, _> = questions.into_iter().map(|q| (q.id, q.title)).collect();
+            for (id, score) in matches {
+                let title = titles.get(&id).expect("Every match belongs to a question");
+                println!("{score:.3}\t{title}")
+            }
+        }
+        Command::Cluster {
+            embedding_opt,
+            topics,
+            titles,
+            tags,
+        } => {
+            let client = Client::new(&embedding_opt.model_opt.token)?;
+            let (embeddings, questions) =
+                embed_questions(&embedding_opt.posts_xml, &embedding_opt.model_opt, &client)
+                    .await?;
+            let params = TopicParams {
+                topics,
+                top_tags: tags,
+                ..TopicParams::default()
+            };
+            let topics = cluster_topics(&embeddings, &questions, params);
+            let question_titles: HashMap<_, _> =
+                questions.iter().map(|q| (q.id, q.title.as_str())).collect();
+            let topics: Vec<_> = topics
+                .iter()
+                .map(|topic| {
+                    let questions: Vec<_> = topic
+                        .questions
+                        .iter()
+                        .take(titles)
+                        .map(|&(id, _)| json!({ "id": id, "title": question_titles[&id] }))
+                        .collect();
+                    let tags: Vec<_> = topic
+                        .tags
+                        .iter()
+                        .map(|(tag, count)| json!({ "tag": tag, "count": count }))
+                        .collect();
+                    json!({
+                        "size": topic.questions.len(),
+                        "questions": questions,
+                        "tags": tags,
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&topics)?);
+        }
+        Command::Search {
+            posts_xml,
+            query,
+            top,
+        } => {
+            let query = Query::parse(&query)?;
+            let index = open_inverted_index(&posts_xml)?;
+            let matches = index.search(&query, top);
+            if matches.is_empty() {
+                eprintln!("There are no questions matching your query.");
+            }
+            for (id, score) in matches {
+                let title = index.title(id).expect("Every match belongs to a question");
+                println!("{score:.3}\t{title}")
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Current day, counted in days since the first of January 1970.
+fn today() -> i64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is set after 1970");
+    (since_epoch.as_secs() / (24 * 60 * 60)) as i64
+}
 
-            let index_title =
-                title_embeddings.find_most_similar(&Embedding::try_from_slice(question_embedding)?);
+/// The `k` questions most similar to the question in `title_opt`, as tuples of post id and
+/// similarity, together with all the questions compared. With `--diversity` the questions are
+/// selected from more candidates using maximal marginal relevance. Only questions in `allowed` are
+/// returned, unless it is `None`.
+async fn semantic_search(
+    title_opt: TitleOpt,
+    index_opt: &IndexOpt,
+    diversity_opt: &DiversityOpt,
+    allowed: Option<&HashSet<u64>>,
+    k: usize,
+) -> Result<(Vec<(u64, f32)>, Vec<Document>), Error> {
+    let TitleOpt {
+        embedding_opt,
+        question,
+    } = title_opt;
+    let (_, query_representation) = embedding_opt.model_opt.representations();
+    let client = Client::new(&embedding_opt.model_opt.token)?;
+    let (embeddings, questions) =
+        embed_questions(&embedding_opt.posts_xml, &embedding_opt.model_opt, &client).await?;
 
-            let best_title = &titles[index_title];
+    let question_embedding = Embedding::from_text(&client, &question, query_representation).await?;
 
-            println!("{best_title}")
+    let index = open_index(index_opt, &embedding_opt.posts_xml, &embeddings)?;
+    let search = |k| match allowed {
+        None => search_post_embeddings(index.as_ref(), &embeddings, &question_embedding, k),
+        Some(allowed) => search_post_embeddings_where(
+            index.as_ref(),
+            &embeddings,
+            &question_embedding,
+            k,
+            |id| allowed.contains(&id),
+        ),
+    };
+    let ranking = match diversity_opt.diversity {
+        None => search(k)?,
+        Some(lambda) => {
+            let candidates = search(k.max(diversity_opt.diversity_candidates))?;
+            Mmr { lambda }.select(&embeddings, &candidates, k)
+        }
+    };
+    let ranking = ranking
+        .into_iter()
+        .map(|(index, similarity)| (embeddings.id(index), similarity))
+        .collect();
+    Ok((ranking, questions))
+}
+
+/// Embeddings of all questions in the posts, together with the questions. Embeds the questions,
+/// unless their embeddings are cached already.
+async fn embed_questions(
+    posts_xml: &Path,
+    opt: &ModelOpt,
+    client: &Client,
+) -> Result<(Embeddings, Vec<Document>), Error> {
+    let (document_representation, query_representation) = opt.representations();
+    let chunking = Chunking {
+        max_words: opt.chunk_words,
+        overlap: opt.chunk_overlap,
+    };
+    let questions = read_documents(posts_xml, opt.content.into(), chunking)?;
+    // Each chunk of each question, as tuple of post id and text
+    let documents: Vec<_> = questions
+        .iter()
+        .flat_map(|question| {
+            question
+                .chunks
+                .iter()
+                .map(|chunk| (question.id, chunk.as_str()))
+        })
+        .collect();
+
+    // Load embeddings which have already been calculated. Embeddings are written to the cache as
+    // soon as they arrive, so we can pick up where a previous run stopped.
+    let embedding_path = posts_xml.with_extension("emb");
+    let metadata = CacheMetadata {
+        model: MODEL.to_owned(),
+        representation: document_representation,
+        query_representation,
+        dimension: EMBEDDING_SIZE as u32,
+        source_hash: source_hash(documents.iter().copied()),
+    };
+    let mut cache = match EmbeddingCache::open(&embedding_path, &metadata) {
+        Err(LibError::CacheOutdated) => {
+            eprintln!("Posts changed. Reuse embeddings of unchanged posts.");
+            EmbeddingCache::migrate(&embedding_path, &metadata, &documents)?
+        }
+        Err(LibError::RepresentationChanged(change)) => {
+            eprintln!("{change} Embed all questions anew.");
+            EmbeddingCache::recreate(&embedding_path, &metadata)?
+        }
+        cache => cache?,
+    };
+    let missing = if cache.len() < documents.len() {
+        cache.missing(&documents)?
+    } else {
+        Vec::new()
+    };
+    if missing.is_empty() {
+        eprintln!("Use cached embeddings");
+    } else {
+        eprintln!(
+            "Generate embeddings. {} of {} already cached.",
+            documents.len() - missing.len(),
+            documents.len()
+        );
+        for (id, text) in missing {
+            let record = Record {
+                id,
+                text_hash: text_hash(text),
+                embedding: Embedding::from_text(client, text, document_representation).await?,
+            };
+            cache.append(&record)?;
         }
     }
-    Ok(())
+    let embeddings = cache.into_mapped()?;
+    Ok((embeddings, questions))
 }
 
-fn extract_titles(posts_xml: &Path) -> Result<Vec<String>, Error> {
-    let mut titles = Vec::new();
-    let mut reader = PostReader::new(posts_xml)?;
-    while let Some(post) = reader.next_post()? {
-        if let Post::Question { title, .. } = post {
-            titles.push(title);
+/// Loads the inverted index stored next to the posts. It is built anew if it does not exist yet,
+/// can not be read, or the posts changed since.
+fn open_inverted_index(posts_xml: &Path) -> Result<InvertedIndex, Error> {
+    let path = posts_xml.with_extension("idx");
+    let stored = match File::open(&path) {
+        Ok(file) => InvertedIndex::from_reader(&mut BufReader::new(file)).ok(),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error.into()),
+    };
+    match stored {
+        Some(index) if index.fits(posts_xml) => Ok(index),
+        _ => {
+            eprintln!("Build inverted index");
+            let index = InvertedIndex::build(posts_xml, Bm25Params::default())?;
+            write_atomically(&path, |file| index.write(file))?;
+            Ok(index)
         }
     }
-    Ok(titles)
 }
 
-fn open_embedddings_cache(path: &Path) -> Result<Option<File>, io::Error> {
-    match File::open(path) {
-        Ok(file) => Ok(Some(file)),
-        Err(error) => {
-            if error.kind() == io::ErrorKind::NotFound {
-                Ok(None)
-            } else {
-                Err(error)
-            }
+/// Loads the languages of the questions stored next to the posts. They are detected anew if they
+/// have not been stored yet, can not be read, or the posts changed since.
+fn open_languages(posts_xml: &Path) -> Result<Languages, Error> {
+    let path = posts_xml.with_extension("lang");
+    let stored = match File::open(&path) {
+        Ok(file) => Languages::from_reader(&mut BufReader::new(file)).ok(),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error.into()),
+    };
+    match stored {
+        Some(languages) if languages.fits(posts_xml) => Ok(languages),
+        _ => {
+            eprintln!("Detect languages");
+            let languages = Languages::detect(posts_xml)?;
+            write_atomically(&path, |file| languages.write(file))?;
+            Ok(languages)
+        }
+    }
+}
+
+/// Writes an index to `path`. Writes to a temporary file first, which replaces the file at `path`
+/// once complete, so neither concurrent nor interrupted runs leave a partial index behind.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> Result<(), Error> {
+    let mut extension = path.extension().unwrap_or_default().to_owned();
+    extension.push(format!(".{}", std::process::id()));
+    let temporary = path.with_extension(extension);
+    let mut file = BufWriter::new(File::create(&temporary)?);
+    write(&mut file)?;
+    file.flush()?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Loads the index selected by the user. Approximate indices are built and stored next to the
+/// posts, if they do not exist yet, can not be read, or have been built for different embeddings
+/// or parameters.
+fn open_index(
+    opt: &IndexOpt,
+    posts_xml: &Path,
+    embeddings: &Embeddings,
+) -> Result<Box<dyn VectorIndex + Sync>, Error> {
+    match opt.index {
+        IndexKind::Exact => Ok(Box::new(ExactSearch)),
+        IndexKind::Hnsw => {
+            let params = HnswParams {
+                m: opt.hnsw_m,
+                ..HnswParams::default()
+            };
+            let path = posts_xml.with_extension("hnsw");
+            let stored = match File::open(&path) {
+                Ok(file) => Hnsw::from_reader(&mut BufReader::new(file)).ok(),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+                Err(error) => return Err(error.into()),
+            };
+            let mut hnsw = match stored {
+                Some(hnsw) if hnsw.fits(embeddings) && hnsw.params().m == params.m => hnsw,
+                _ => {
+                    eprintln!("Build HNSW index");
+                    let hnsw = Hnsw::build(embeddings, params);
+                    write_atomically(&path, |file| hnsw.write(file))?;
+                    hnsw
+                }
+            };
+            hnsw.set_ef(opt.ef);
+            Ok(Box::new(hnsw))
+        }
+        IndexKind::IvfPq => {
+            let params = IvfPqParams {
+                lists: opt.ivf_lists,
+                ..IvfPqParams::default()
+            };
+            let path = posts_xml.with_extension("ivf");
+            let stored = match File::open(&path) {
+                Ok(file) => IvfPq::from_reader(&mut BufReader::new(file)).ok(),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+                Err(error) => return Err(error.into()),
+            };
+            let mut ivf_pq = match stored {
+                Some(ivf_pq) if ivf_pq.fits(embeddings) && ivf_pq.params() == params => ivf_pq,
+                _ => {
+                    eprintln!("Build IVF-PQ index");
+                    let ivf_pq = IvfPq::build(embeddings, params)?;
+                    write_atomically(&path, |file| ivf_pq.write(file))?;
+                    ivf_pq
+                }
+            };
+            ivf_pq.set_nprobe(opt.nprobe);
+            ivf_pq.set_rerank(opt.rerank);
+            Ok(Box::new(ivf_pq))
         }
     }
 }
@@ -0,0 +1,94 @@
+use crate::Embeddings;
+
+/// Maximal marginal relevance. Selects results which are relevant to the query, but not similar
+/// to the results selected before them, so near-duplicate questions do not crowd out other
+/// topics. See <https://www.cs.cmu.edu/~jgc/publication/The_Use_MMR_Diversity_Based_LTMIR_1998.pdf>.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mmr {
+    /// Trades relevance for diversity. `1` ranks by relevance alone, `0` by diversity alone.
+    pub lambda: f32,
+}
+
+impl Default for Mmr {
+    fn default() -> Self {
+        Self { lambda: 0.7 }
+    }
+}
+
+impl Mmr {
+    /// Selects `k` of the `candidates`, given as tuples of embedding index and relevance. Each
+    /// step picks the candidate maximizing `lambda * relevance - (1 - lambda) * redundancy`, where
+    /// redundancy is its highest similarity to any candidate selected so far. Returns the selected
+    /// candidates with their relevance, in the order they have been selected. Ties are broken in
+    /// favour of the earlier candidate.
+    pub fn select(
+        &self,
+        embeddings: &Embeddings,
+        candidates: &[(usize, f32)],
+        k: usize,
+    ) -> Vec<(usize, f32)> {
+        let records = embeddings.records();
+        let mut remaining: Vec<(usize, f32)> = candidates.to_vec();
+        // Highest similarity of each remaining candidate to the selected ones
+        let mut redundancy = vec![f32::MIN; remaining.len()];
+        let mut selected = Vec::with_capacity(k.min(remaining.len()));
+        while selected.len() < k && !remaining.is_empty() {
+            let mut best = 0;
+            let mut best_score = f32::MIN;
+            for (position, &(_, relevance)) in remaining.iter().enumerate() {
+                let penalty = if selected.is_empty() {
+                    0.
+                } else {
+                    redundancy[position]
+                };
+                let score = self.lambda * relevance - (1. - self.lambda) * penalty;
+                if score > best_score {
+                    best = position;
+                    best_score = score;
+                }
+            }
+            let (index, relevance) = remaining.remove(best);
+            redundancy.remove(best);
+            // Embeddings are normalized, so the dot product is their cosine similarity
+            let picked = &records[index].embedding;
+            for (&(other, _), redundancy) in remaining.iter().zip(&mut redundancy) {
+                *redundancy = redundancy.max(picked.dot(&records[other].embedding));
+            }
+            selected.push((index, relevance));
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mmr;
+    use crate::{test_util::record, Embeddings};
+
+    fn embeddings() -> Embeddings {
+        Embeddings::from_vec(vec![
+            record(1, &[1., 0.]),
+            // Near duplicate of the first one
+            record(2, &[1., 0.05]),
+            record(3, &[0., 1.]),
+        ])
+    }
+
+    #[test]
+    fn skip_near_duplicates() {
+        let candidates = [(0, 0.9), (1, 0.89), (2, 0.6)];
+
+        let selected = Mmr { lambda: 0.5 }.select(&embeddings(), &candidates, 2);
+
+        assert_eq!(vec![(0, 0.9), (2, 0.6)], selected);
+    }
+
+    #[test]
+    fn lambda_one_keeps_relevance_order() {
+        let candidates = [(0, 0.9), (1, 0.89), (2, 0.6)];
+
+        let selected = Mmr { lambda: 1. }.select(&embeddings(), &candidates, 3);
+
+        assert_eq!(candidates.to_vec(), selected);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::Error;
+
+/// Position in a ranking after which the next page starts. Shown to users as an opaque string, see
+/// [`Cursor::encode`].
+///
+/// A cursor remembers the score and post id of the last result of a page, rather than its position,
+/// so results stay on their page, even if questions are added or removed in between.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    /// Post id of the last result shown
+    pub id: u64,
+    /// Score of the last result shown
+    pub score: f32,
+    /// Number of results shown before the next page. Only tells how deep to search, so the next
+    /// page can be found.
+    pub position: usize,
+}
+
+/// Length of an encoded cursor in hex digits: post id, bits of the score and position.
+const ENCODED_LEN: usize = 16 + 8 + 16;
+
+impl Cursor {
+    /// Cursor as a string of hex digits, safe to use in URLs and command lines.
+    pub fn encode(&self) -> String {
+        format!(
+            "{:016x}{:08x}{:016x}",
+            self.id,
+            self.score.to_bits(),
+            self.position
+        )
+    }
+
+    /// Reads a cursor written by [`Cursor::encode`].
+    pub fn decode(text: &str) -> Result<Cursor, Error> {
+        let invalid =
+            || Error::InvalidCursor(format!("'{text}' has not been returned by a search"));
+        if text.len() != ENCODED_LEN || !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        let id = u64::from_str_radix(&text[..16], 16).map_err(|_| invalid())?;
+        let score = f32::from_bits(u32::from_str_radix(&text[16..24], 16).map_err(|_| invalid())?);
+        let position = u64::from_str_radix(&text[24..], 16).map_err(|_| invalid())?;
+        Ok(Cursor {
+            id,
+            score,
+            position: usize::try_from(position).map_err(|_| invalid())?,
+        })
+    }
+
+    /// Whether the result comes after the cursor, if ordered by score, highest first, and ties by
+    /// post id.
+    fn is_followed_by(&self, (id, score): (u64, f32)) -> bool {
+        score
+            .total_cmp(&self.score)
+            .reverse()
+            .then(id.cmp(&self.id))
+            .is_gt()
+    }
+}
+
+/// Where a page of results starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageStart {
+    /// Skips this many results. Zero starts with the first page.
+    Offset(usize),
+    /// Starts after the last result of the previous page.
+    After(Cursor),
+}
+
+impl PageStart {
+    /// Number of results before the page, which is the least number of results to search for in
+    /// addition to the page itself.
+    pub fn skipped(&self) -> usize {
+        match self {
+            PageStart::Offset(offset) => *offset,
+            PageStart::After(cursor) => cursor.position,
+        }
+    }
+}
+
+/// Results of a single page, together with the cursor to the next one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page {
+    /// Tuples of post id and score, in the order of the ranking.
+    pub results: Vec<(u64, f32)>,
+    /// Starts the next page. `None` if this is the last page.
+    pub next: Option<Cursor>,
+}
+
+/// Cuts the page of at most `limit` results starting at `start` out of a `ranking`, given as tuples
+/// of post id and score.
+///
+/// A cursor continues after the result with its post id. If that result is no longer part of the
+/// ranking, e.g. because its question has been deleted, the page starts with the first result
+/// scoring lower, or scoring the same with a higher id. This requires the ranking to be ordered by
+/// score, highest first, and ties by post id, like the rankings of this crate are. Rankings
+/// ordered otherwise, e.g. for diversity, page correctly as long as the result is still there.
+pub fn page(ranking: &[(u64, f32)], start: PageStart, limit: usize) -> Page {
+    let first = match start {
+        PageStart::Offset(offset) => offset.min(ranking.len()),
+        PageStart::After(cursor) => match ranking.iter().position(|&(id, _)| id == cursor.id) {
+            Some(position) => position + 1,
+            None => ranking
+                .iter()
+                .position(|&result| cursor.is_followed_by(result))
+                .unwrap_or(ranking.len()),
+        },
+    };
+    let end = first.saturating_add(limit).min(ranking.len());
+    let results = ranking[first..end].to_vec();
+    let next = match results.last() {
+        Some(&(id, score)) if end < ranking.len() => Some(Cursor {
+            id,
+            score,
+            position: start.skipped().saturating_add(results.len()),
+        }),
+        _ => None,
+    };
+    Page { results, next }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{page, Cursor, PageStart};
+
+    const RANKING: [(u64, f32); 5] = [(7, 0.9), (2, 0.8), (5, 0.8), (1, 0.5), (3, 0.1)];
+
+    #[test]
+    fn walk_pages_with_cursors() {
+        let first = page(&RANKING, PageStart::Offset(0), 2);
+        let cursor = Cursor::decode(&first.next.unwrap().encode()).unwrap();
+        let second = page(&RANKING, PageStart::After(cursor), 2);
+        let third = page(&RANKING, PageStart::After(second.next.unwrap()), 2);
+
+        assert_eq!(vec![(7, 0.9), (2, 0.8)], first.results);
+        assert_eq!(vec![(5, 0.8), (1, 0.5)], second.results);
+        assert_eq!(vec![(3, 0.1)], third.results);
+        assert_eq!(None, third.next);
+        assert_eq!(page(&RANKING, PageStart::Offset(2), 2), second);
+    }
+
+    #[test]
+    fn continue_after_removed_result() {
+        let cursor = page(&RANKING, PageStart::Offset(0), 2).next.unwrap();
+        // The last result of the first page has been removed in between
+        let ranking: Vec<_> = RANKING.into_iter().filter(|&(id, _)| id != 2).collect();
+
+        let next = page(&ranking, PageStart::After(cursor), 2);
+
+        assert_eq!(vec![(5, 0.8), (1, 0.5)], next.results);
+    }
+
+    #[test]
+    fn reject_malformed_cursor() {
+        assert!(Cursor::decode("not a cursor").is_err());
+        assert!(Cursor::decode(&"g".repeat(40)).is_err());
+    }
+}
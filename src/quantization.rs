@@ -0,0 +1,354 @@
+use std::{
+    io::{self, BufRead, Write},
+    mem::size_of,
+};
+
+use crate::{
+    embedding::{top_k, EMBEDDING_SIZE},
+    Embedding, Embeddings,
+};
+
+/// A compact approximation of an [`Embedding`].
+pub trait Quantized: Copy {
+    /// First bytes of files storing this kind of quantized embeddings.
+    const MAGIC: &'static [u8; 8];
+    /// Size of a quantized embedding in binary form.
+    const SIZE: usize;
+
+    fn quantize(embedding: &Embedding) -> Self;
+
+    /// Estimates the cosine similarity of the original embeddings. Higher is more similar.
+    fn score(&self, other: &Self) -> f32;
+
+    /// Write the quantized embedding into a binary buffer of [`Self::SIZE`].
+    fn write_to_bytes(&self, buf: &mut [u8]);
+
+    /// Load quantized embedding from a binary buffer of [`Self::SIZE`].
+    fn read_from_bytes(buf: &[u8]) -> Self;
+}
+
+/// Embedding quantized to one signed byte per dimension. Takes a quarter of the space of the
+/// original.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Int8Embedding {
+    /// Multiply the values with the scale to approximate the original embedding.
+    pub scale: f32,
+    pub values: [i8; EMBEDDING_SIZE],
+}
+
+impl Int8Embedding {
+    /// Approximation of the original embedding.
+    pub fn dequantize(&self) -> Embedding {
+        Embedding(self.values.map(|value| value as f32 * self.scale))
+    }
+}
+
+impl Quantized for Int8Embedding {
+    const MAGIC: &'static [u8; 8] = b"SSEQINT8";
+    const SIZE: usize = size_of::<f32>() + EMBEDDING_SIZE;
+
+    fn quantize(embedding: &Embedding) -> Self {
+        let max = embedding
+            .0
+            .iter()
+            .fold(0f32, |max, value| max.max(value.abs()));
+        let scale = max / i8::MAX as f32;
+        let values = if scale == 0. {
+            [0; EMBEDDING_SIZE]
+        } else {
+            embedding.0.map(|value| (value / scale).round() as i8)
+        };
+        Self { scale, values }
+    }
+
+    fn score(&self, other: &Self) -> f32 {
+        // The scales cancel out in the cosine similarity, so we stay in integer arithmetic.
+        let (mut dot, mut norm_self, mut norm_other) = (0i32, 0i32, 0i32);
+        for (&a, &b) in self.values.iter().zip(&other.values) {
+            let (a, b) = (a as i32, b as i32);
+            dot += a * b;
+            norm_self += a * a;
+            norm_other += b * b;
+        }
+        if norm_self == 0 || norm_other == 0 {
+            return 0.;
+        }
+        dot as f32 / ((norm_self as f32).sqrt() * (norm_other as f32).sqrt())
+    }
+
+    fn write_to_bytes(&self, buf: &mut [u8]) {
+        buf[..size_of::<f32>()].copy_from_slice(&self.scale.to_le_bytes());
+        for (byte, value) in buf[size_of::<f32>()..].iter_mut().zip(self.values) {
+            *byte = value as u8;
+        }
+    }
+
+    fn read_from_bytes(buf: &[u8]) -> Self {
+        let scale = f32::from_le_bytes(buf[..size_of::<f32>()].try_into().unwrap());
+        let mut values = [0; EMBEDDING_SIZE];
+        for (value, byte) in values.iter_mut().zip(&buf[size_of::<f32>()..]) {
+            *value = *byte as i8;
+        }
+        Self { scale, values }
+    }
+}
+
+/// Number of 64 bit words in a [`BinaryEmbedding`].
+const BINARY_WORDS: usize = EMBEDDING_SIZE / 64;
+
+/// Embedding quantized to a single bit per dimension, which is set for positive values. Takes a
+/// 32nd of the space of the original. Comparing these is very fast, yet coarse. Best used to find
+/// candidates which are then scored again using more precise embeddings.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct BinaryEmbedding(pub [u64; BINARY_WORDS]);
+
+impl BinaryEmbedding {
+    /// Number of dimensions in which the signs differ.
+    pub fn hamming_distance(&self, other: &Self) -> u32 {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum()
+    }
+}
+
+impl Quantized for BinaryEmbedding {
+    const MAGIC: &'static [u8; 8] = b"SSEQBIN1";
+    const SIZE: usize = BINARY_WORDS * size_of::<u64>();
+
+    fn quantize(embedding: &Embedding) -> Self {
+        let mut words = [0u64; BINARY_WORDS];
+        for (dimension, value) in embedding.0.iter().enumerate() {
+            if *value > 0. {
+                words[dimension / 64] |= 1 << (dimension % 64);
+            }
+        }
+        Self(words)
+    }
+
+    fn score(&self, other: &Self) -> f32 {
+        // Maps a hamming distance of zero to 1 and one of all dimensions to -1, like the cosine
+        // similarity of identical and opposite vectors.
+        1. - 2. * self.hamming_distance(other) as f32 / EMBEDDING_SIZE as f32
+    }
+
+    fn write_to_bytes(&self, buf: &mut [u8]) {
+        for (bytes, word) in buf.chunks_exact_mut(size_of::<u64>()).zip(self.0) {
+            bytes.copy_from_slice(&word.to_le_bytes())
+        }
+    }
+
+    fn read_from_bytes(buf: &[u8]) -> Self {
+        let mut words = [0u64; BINARY_WORDS];
+        for (word, bytes) in words.iter_mut().zip(buf.chunks_exact(size_of::<u64>())) {
+            *word = u64::from_le_bytes(bytes.try_into().unwrap());
+        }
+        Self(words)
+    }
+}
+
+/// Quantized embeddings of many posts. A compact alternative to [`Embeddings`].
+#[derive(Debug, PartialEq)]
+pub struct QuantizedEmbeddings<Q> {
+    /// Post id of each embedding
+    ids: Vec<u64>,
+    embeddings: Vec<Q>,
+}
+
+/// Embeddings using a single byte per dimension.
+pub type Int8Embeddings = QuantizedEmbeddings<Int8Embedding>;
+/// Embeddings using a single bit per dimension.
+pub type BinaryEmbeddings = QuantizedEmbeddings<BinaryEmbedding>;
+
+impl<Q: Quantized> QuantizedEmbeddings<Q> {
+    /// Quantizes each of the embeddings. Positions and ids are the same as in `embeddings`.
+    pub fn from_embeddings(embeddings: &Embeddings) -> Self {
+        let (ids, embeddings) = embeddings
+            .records()
+            .iter()
+            .map(|record| (record.id, Q::quantize(&record.embedding)))
+            .unzip();
+        Self { ids, embeddings }
+    }
+
+    pub fn from_reader(read: &mut impl BufRead) -> Result<Self, io::Error> {
+        let mut magic = [0u8; 8];
+        read.read_exact(&mut magic)?;
+        if &magic != Q::MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "File does not contain embeddings of the expected quantization.",
+            ));
+        }
+        let mut count = [0u8; size_of::<u64>()];
+        read.read_exact(&mut count)?;
+        let count = u64::from_le_bytes(count) as usize;
+        let mut ids = Vec::with_capacity(count);
+        let mut embeddings = Vec::with_capacity(count);
+        let mut buf = vec![0u8; size_of::<u64>() + Q::SIZE];
+        for _ in 0..count {
+            read.read_exact(&mut buf)?;
+            let (id, embedding) = buf.split_at(size_of::<u64>());
+            ids.push(u64::from_le_bytes(id.try_into().unwrap()));
+            embeddings.push(Q::read_from_bytes(embedding));
+        }
+        Ok(Self { ids, embeddings })
+    }
+
+    pub fn write(&self, write: &mut impl Write) -> Result<(), io::Error> {
+        write.write_all(Q::MAGIC)?;
+        write.write_all(&(self.len() as u64).to_le_bytes())?;
+        let mut buf = vec![0u8; size_of::<u64>() + Q::SIZE];
+        for (id, embedding) in self.ids.iter().zip(&self.embeddings) {
+            let (id_bytes, embedding_bytes) = buf.split_at_mut(size_of::<u64>());
+            id_bytes.copy_from_slice(&id.to_le_bytes());
+            embedding.write_to_bytes(embedding_bytes);
+            write.write_all(&buf)?;
+        }
+        write.flush()?;
+        Ok(())
+    }
+
+    /// Number of embeddings
+    pub fn len(&self) -> usize {
+        self.embeddings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.embeddings.is_empty()
+    }
+
+    /// Id of the post the embedding at `index` has been computed for.
+    pub fn id(&self, index: usize) -> u64 {
+        self.ids[index]
+    }
+
+    /// Index of the embedding most similar to `needle`, judged by the quantized embeddings alone.
+    /// `None` if there are no embeddings.
+    pub fn find_most_similar(&self, needle: &Embedding) -> Option<usize> {
+        self.candidates(needle, 1).first().copied()
+    }
+
+    /// Indices of the `n` embeddings most similar to `needle`, judged by the quantized embeddings
+    /// alone. Most similar first.
+    pub fn candidates(&self, needle: &Embedding, n: usize) -> Vec<usize> {
+        let needle = Q::quantize(needle);
+        let scored = self
+            .embeddings
+            .iter()
+            .map(|embedding| embedding.score(&needle))
+            .enumerate();
+        top_k(scored, n)
+            .into_iter()
+            .map(|(index, _score)| index)
+            .collect()
+    }
+
+    /// Finds `num_candidates` using the quantized embeddings first and then picks the most similar
+    /// one of them, using the full precision `originals`. `originals` must be the embeddings this
+    /// instance has been quantized from. `None` if no candidate has a well defined similarity.
+    pub fn find_most_similar_rescored(
+        &self,
+        needle: &Embedding,
+        num_candidates: usize,
+        originals: &Embeddings,
+    ) -> Option<usize> {
+        debug_assert_eq!(self.len(), originals.len());
+        let normalized = needle.normalize();
+        let rescored = self
+            .candidates(needle, num_candidates)
+            .into_iter()
+            .map(|index| {
+                debug_assert_eq!(self.ids[index], originals.id(index));
+                let similarity = originals.records()[index].embedding.dot(&normalized);
+                (index, similarity)
+            })
+            .filter(|(_index, similarity)| !similarity.is_nan());
+        top_k(rescored, 1).first().map(|&(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{text_hash, Record};
+
+    use super::*;
+
+    fn embeddings(vectors: &[Embedding]) -> Embeddings {
+        Embeddings::from_vec(
+            vectors
+                .iter()
+                .enumerate()
+                .map(|(index, embedding)| Record {
+                    id: index as u64 + 1,
+                    text_hash: text_hash(""),
+                    embedding: *embedding,
+                })
+                .collect(),
+        )
+    }
+
+    fn ramp(offset: f32) -> Embedding {
+        Embedding(std::array::from_fn(|i| (i as f32 - 64. + offset) / 64.))
+    }
+
+    #[test]
+    fn int8_approximates_original() {
+        let original = ramp(0.5);
+
+        let approximation = Int8Embedding::quantize(&original).dequantize();
+
+        for (a, b) in original.0.iter().zip(approximation.0) {
+            assert!((a - b).abs() < 0.01)
+        }
+    }
+
+    #[test]
+    fn binary_keeps_signs() {
+        let mut embedding = Embedding::new();
+        embedding.0[0] = 1.;
+        embedding.0[1] = -1.;
+        embedding.0[127] = 0.5;
+
+        let binary = BinaryEmbedding::quantize(&embedding);
+
+        assert_eq!(BinaryEmbedding([1, 1 << 63]), binary);
+        assert_eq!(2, binary.hamming_distance(&BinaryEmbedding([0, 0])));
+    }
+
+    #[test]
+    fn quantized_embeddings_to_and_fro_bytes() {
+        let embeddings = embeddings(&[ramp(0.), ramp(10.)]);
+        let int8 = Int8Embeddings::from_embeddings(&embeddings);
+        let binary = BinaryEmbeddings::from_embeddings(&embeddings);
+
+        let mut buf = Vec::new();
+        int8.write(&mut buf).unwrap();
+        binary.write(&mut buf).unwrap();
+        let mut read = Cursor::new(buf);
+
+        assert_eq!(int8, Int8Embeddings::from_reader(&mut read).unwrap());
+        assert_eq!(binary, BinaryEmbeddings::from_reader(&mut read).unwrap());
+    }
+
+    #[test]
+    fn rescoring_picks_most_similar_original() {
+        // Both embeddings have the same signs as the needle, so the binary quantization can not
+        // tell them apart, but the originals can.
+        let mut close = Embedding([1.; EMBEDDING_SIZE]);
+        close.0[0] = 0.9;
+        let mut far = Embedding([1.; EMBEDDING_SIZE]);
+        far.0[0] = 20.;
+        let embeddings = embeddings(&[far, close]);
+        let binary = BinaryEmbeddings::from_embeddings(&embeddings);
+
+        let best = binary
+            .find_most_similar_rescored(&Embedding([1.; EMBEDDING_SIZE]), 2, &embeddings)
+            .unwrap();
+
+        assert_eq!(2, binary.id(best));
+    }
+}
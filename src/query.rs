@@ -0,0 +1,264 @@
+use crate::{lexical::analyze, Error};
+
+/// Keyword query against an [`crate::InvertedIndex`]. Terms are analyzed the same way as the
+/// indexed texts, i.e. stopwords are dropped and words are stemmed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// Documents containing the term
+    Term(String),
+    /// Documents containing the terms in this order. Tuples of the offset of each term within the
+    /// phrase and the term. The first offset is zero.
+    Phrase(Vec<(u32, String)>),
+    /// Documents matching all of the queries. Matches every document, if empty.
+    And(Vec<Query>),
+    /// Documents matching any of the queries. Matches no document, if empty.
+    Or(Vec<Query>),
+    /// Documents not matching the query
+    Not(Box<Query>),
+}
+
+impl Query {
+    /// Parses a query like `"layer height" AND -resin`.
+    ///
+    /// * Words separated by whitespace must all be contained in a document. `AND` may be used to
+    ///   make this explicit.
+    /// * `OR` between two words, matches documents containing either of them. `AND` binds
+    ///   stronger than `OR`.
+    /// * Words in double quotes form a phrase, whose words must be adjacent and in order.
+    /// * A leading `-` or `NOT` excludes documents.
+    /// * Parentheses group queries.
+    ///
+    /// Words consisting only of stopwords are ignored. A query without any words matches nothing.
+    pub fn parse(text: &str) -> Result<Query, Error> {
+        let tokens = lex(text)?;
+        let mut parser = Parser { tokens, next: 0 };
+        let query = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(Error::InvalidQuery(format!("Unexpected {token:?}")));
+        }
+        Ok(query.unwrap_or(Query::Or(Vec::new())))
+    }
+
+    /// Matches documents containing any of the words in `text`. Meant for natural language
+    /// questions, which rarely share all their words with a document.
+    pub fn any_of(text: &str) -> Query {
+        let mut terms: Vec<Query> = Vec::new();
+        for (_, term) in analyze(text) {
+            let term = Query::Term(term);
+            if !terms.contains(&term) {
+                terms.push(term);
+            }
+        }
+        Query::Or(terms)
+    }
+
+    /// Terms and phrases the query asks for, i.e. all of them, which are not excluded.
+    pub fn positive_leaves(&self) -> Vec<&Query> {
+        match self {
+            Query::Term(_) | Query::Phrase(_) => vec![self],
+            Query::And(queries) | Query::Or(queries) => {
+                queries.iter().flat_map(Query::positive_leaves).collect()
+            }
+            Query::Not(_) => Vec::new(),
+        }
+    }
+
+    /// Query for a word or phrase. `None` if it only consists of stopwords.
+    fn words(text: &str) -> Option<Query> {
+        let mut terms: Vec<(u32, String)> = analyze(text).collect();
+        let first = terms.first()?.0;
+        if terms.len() == 1 {
+            return Some(Query::Term(terms.pop().unwrap().1));
+        }
+        for (offset, _) in &mut terms {
+            *offset -= first;
+        }
+        Some(Query::Phrase(terms))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    Minus,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn lex(text: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let end = text[start + 1..]
+                    .find('"')
+                    .ok_or_else(|| Error::InvalidQuery("Unterminated phrase".to_owned()))?;
+                tokens.push(Token::Phrase(text[start + 1..start + 1 + end].to_owned()));
+                while chars
+                    .next_if(|&(index, _)| index <= start + 1 + end)
+                    .is_some()
+                {}
+            }
+            '-' if chars.peek().is_some_and(|&(_, next)| !next.is_whitespace()) => {
+                tokens.push(Token::Minus)
+            }
+            _ => {
+                let mut end = text.len();
+                while let Some(&(index, next)) = chars.peek() {
+                    if next.is_whitespace() || matches!(next, '(' | ')' | '"') {
+                        end = index;
+                        break;
+                    }
+                    chars.next();
+                }
+                let token = match &text[start..end] {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    word => Token::Word(word.to_owned()),
+                };
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser. Each method returns `None` for queries which only consist of
+/// stopwords.
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Option<Query>, Error> {
+        let mut queries: Vec<Query> = self.and()?.into_iter().collect();
+        while self.peek() == Some(&Token::Or) {
+            self.advance();
+            queries.extend(self.and()?);
+        }
+        Ok(combine(queries, Query::Or))
+    }
+
+    fn and(&mut self) -> Result<Option<Query>, Error> {
+        let mut queries = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(Token::Or) | Some(Token::Close) => break,
+                Some(Token::And) => {
+                    self.advance();
+                }
+                Some(_) => queries.extend(self.unary()?),
+            }
+        }
+        Ok(combine(queries, Query::And))
+    }
+
+    fn unary(&mut self) -> Result<Option<Query>, Error> {
+        match self.advance() {
+            Some(Token::Minus) | Some(Token::Not) => {
+                Ok(self.unary()?.map(|query| Query::Not(Box::new(query))))
+            }
+            Some(Token::Word(word)) => Ok(Query::words(&word)),
+            Some(Token::Phrase(phrase)) => Ok(Query::words(&phrase)),
+            Some(Token::Open) => {
+                let query = self.or()?;
+                if self.advance() != Some(Token::Close) {
+                    return Err(Error::InvalidQuery(
+                        "Missing closing parenthesis".to_owned(),
+                    ));
+                }
+                Ok(query)
+            }
+            token => Err(Error::InvalidQuery(format!(
+                "Expected a word, phrase or parenthesis, but found {token:?}"
+            ))),
+        }
+    }
+}
+
+/// Combines several queries with `and` or `or`. A single query is returned as is.
+fn combine(mut queries: Vec<Query>, operator: fn(Vec<Query>) -> Query) -> Option<Query> {
+    match queries.len() {
+        0 => None,
+        1 => queries.pop(),
+        _ => Some(operator(queries)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Query;
+
+    fn term(term: &str) -> Query {
+        Query::Term(term.to_owned())
+    }
+
+    #[test]
+    fn phrase_and_excluded_term() {
+        let query = Query::parse("\"layer height\" AND -resin").unwrap();
+
+        assert_eq!(
+            Query::And(vec![
+                Query::Phrase(vec![(0, "layer".to_owned()), (1, "height".to_owned())]),
+                Query::Not(Box::new(term("resin"))),
+            ]),
+            query
+        );
+    }
+
+    #[test]
+    fn and_binds_stronger_than_or() {
+        let query = Query::parse("nozzle clog OR (bed NOT level)").unwrap();
+
+        assert_eq!(
+            Query::Or(vec![
+                Query::And(vec![term("nozzl"), term("clog")]),
+                Query::And(vec![term("bed"), Query::Not(Box::new(term("level")))]),
+            ]),
+            query
+        );
+    }
+
+    #[test]
+    fn ignore_stopwords() {
+        assert_eq!(term("printer"), Query::parse("the printer").unwrap());
+        assert_eq!(Query::Or(Vec::new()), Query::parse("the").unwrap());
+    }
+
+    #[test]
+    fn hyphenated_words_form_a_phrase() {
+        let query = Query::parse("3d-printer").unwrap();
+
+        assert_eq!(
+            Query::Phrase(vec![(0, "3d".to_owned()), (1, "printer".to_owned())]),
+            query
+        );
+    }
+
+    #[test]
+    fn reject_unbalanced_quotes_and_parentheses() {
+        assert!(Query::parse("\"layer height").is_err());
+        assert!(Query::parse("(layer height").is_err());
+        assert!(Query::parse("layer height)").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use atoi::FromRadix10;
+use atoi::{FromRadix10, FromRadix10Signed};
 use quick_xml::{
     events::{
         attributes::{AttrError, Attribute},
@@ -79,11 +79,23 @@ pub enum Post {
         id: u64,
         title: String,
         body: String,
+        /// Id of the answer accepted by the author of the question, if any.
+        accepted_answer_id: Option<u64>,
+        /// Tags of the question, e.g. `safety`, in the order of the file.
+        tags: Vec<String>,
+        /// Upvotes minus downvotes
+        score: i64,
+        /// Time the question has been asked, e.g. `2016-01-12T18:45:19.963`
+        creation_date: String,
+        view_count: u64,
+        answer_count: u64,
     },
     Answer {
         id: u64,
         parent_id: u64,
         body: String,
+        /// Upvotes minus downvotes
+        score: i64,
     },
     Other,
 }
@@ -94,9 +106,15 @@ impl Post {
     ) -> Result<Self, Error> {
         let mut id = None;
         let mut parent_id = None;
+        let mut accepted_answer_id = None;
         let mut post_type_id = None;
         let mut title = None;
         let mut body = None;
+        let mut tags = Vec::new();
+        let mut score = None;
+        let mut creation_date = None;
+        let mut view_count = None;
+        let mut answer_count = None;
 
         for attr in attributes {
             let attr = attr?;
@@ -104,6 +122,7 @@ impl Post {
                 b"Id" => id = Some(attr.value),
                 b"PostTypeId" => post_type_id = Some(attr.value),
                 b"ParentId" => parent_id = Some(attr.value.clone()),
+                b"AcceptedAnswerId" => accepted_answer_id = Some(attr.value.clone()),
                 b"Title" => {
                     title = Some(
                         attr.unescape_value()
@@ -118,6 +137,22 @@ impl Post {
                             .to_string(),
                     )
                 }
+                b"Score" => score = Some(attr.value.clone()),
+                b"ViewCount" => view_count = Some(attr.value.clone()),
+                b"AnswerCount" => answer_count = Some(attr.value.clone()),
+                b"CreationDate" => {
+                    creation_date = Some(
+                        attr.unescape_value()
+                            .map_err(|_| Error::invalid_xml("Error unmasking attribute"))?
+                            .to_string(),
+                    )
+                }
+                b"Tags" => {
+                    let value = attr
+                        .unescape_value()
+                        .map_err(|_| Error::invalid_xml("Error unmasking attribute"))?;
+                    tags = parse_tags(&value);
+                }
                 _ => (),
             }
         }
@@ -129,7 +164,22 @@ impl Post {
                 let (id, _) = u64::from_radix_10(&id);
                 let title = title.ok_or_else(|| Error::invalid_xml("Missing title in Question"))?;
                 let body = body.ok_or_else(|| Error::invalid_xml("Missing body in Question"))?;
-                Post::Question { id, title, body }
+                let accepted_answer_id =
+                    accepted_answer_id.map(|accepted| u64::from_radix_10(&accepted).0);
+                let score = score.map_or(0, |score| i64::from_radix_10_signed(&score).0);
+                let view_count = view_count.map_or(0, |count| u64::from_radix_10(&count).0);
+                let answer_count = answer_count.map_or(0, |count| u64::from_radix_10(&count).0);
+                Post::Question {
+                    id,
+                    title,
+                    body,
+                    accepted_answer_id,
+                    tags,
+                    score,
+                    creation_date: creation_date.unwrap_or_default(),
+                    view_count,
+                    answer_count,
+                }
             }
             b"2" => {
                 let id = id.ok_or_else(|| Error::invalid_xml("Missing id in Post"))?;
@@ -138,10 +188,12 @@ impl Post {
                     parent_id.ok_or_else(|| Error::invalid_xml("Missing parent_id in Answer"))?;
                 let (parent_id, _) = u64::from_radix_10(&parent_id);
                 let body = body.ok_or_else(|| Error::invalid_xml("Missing body in Answer"))?;
+                let score = score.map_or(0, |score| i64::from_radix_10_signed(&score).0);
                 Post::Answer {
                     id,
                     parent_id,
                     body,
+                    score,
                 }
             }
             _ => Post::Other,
@@ -150,8 +202,30 @@ impl Post {
     }
 }
 
+/// Splits the tags attribute of a question. Older dumps enclose each tag in angle brackets, e.g.
+/// `<safety><health>`, newer ones separate them by pipes, e.g. `|safety|health|`.
+fn parse_tags(value: &str) -> Vec<String> {
+    value
+        .split(['<', '>', '|'])
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
 impl From<AttrError> for Error {
     fn from(source: AttrError) -> Self {
         Error::MalformedXml(source.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_tags;
+
+    #[test]
+    fn tags_in_brackets_or_separated_by_pipes() {
+        assert_eq!(vec!["safety", "health"], parse_tags("<safety><health>"));
+        assert_eq!(vec!["safety", "health"], parse_tags("|safety|health|"));
+        assert!(parse_tags("").is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+use std::{collections::HashSet, future::Future};
+
+use aleph_alpha_client::{cosine_similarity, Client};
+
+use crate::{
+    embedding::semantic_embedding, hybrid::min_max, lexical::analyze, Error, Representation,
+};
+
+/// A signal which scores how well texts fit a query. Usually more expensive than the first stage
+/// of a search, so it is only applied to a few candidates. Implement it to plug in other models,
+/// e.g. a cross-encoder which looks at query and text together.
+pub trait Scorer {
+    /// One score for each of the `texts`, in the same order. Higher scores fit `query` better.
+    fn score<'a>(
+        &'a self,
+        query: &'a str,
+        texts: &'a [&'a str],
+    ) -> impl Future<Output = Result<Vec<f32>, Error>> + 'a;
+}
+
+/// Cosine similarity of embeddings with all dimensions of the model, rather than the compressed
+/// ones stored in the cache. Embeds the query and every candidate using the Aleph Alpha API.
+pub struct FullEmbeddings<'c> {
+    client: &'c Client,
+    document_representation: Representation,
+    query_representation: Representation,
+}
+
+impl<'c> FullEmbeddings<'c> {
+    pub fn new(
+        client: &'c Client,
+        document_representation: Representation,
+        query_representation: Representation,
+    ) -> Self {
+        Self {
+            client,
+            document_representation,
+            query_representation,
+        }
+    }
+}
+
+impl Scorer for FullEmbeddings<'_> {
+    async fn score<'a>(&'a self, query: &'a str, texts: &'a [&'a str]) -> Result<Vec<f32>, Error> {
+        let query = semantic_embedding(self.client, query, self.query_representation, None).await?;
+        let mut scores = Vec::with_capacity(texts.len());
+        for text in texts {
+            let text =
+                semantic_embedding(self.client, text, self.document_representation, None).await?;
+            scores.push(cosine_similarity(&query, &text));
+        }
+        Ok(scores)
+    }
+}
+
+/// Share of the distinct query terms, which also occur in a text. Terms are analyzed like in the
+/// [`crate::InvertedIndex`]. Ranges from 0 to 1. Needs no API calls.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LexicalOverlap;
+
+impl LexicalOverlap {
+    fn overlap(query: &HashSet<String>, text: &str) -> f32 {
+        if query.is_empty() {
+            return 0.;
+        }
+        let text: HashSet<String> = analyze(text).map(|(_, term)| term).collect();
+        query.intersection(&text).count() as f32 / query.len() as f32
+    }
+}
+
+impl Scorer for LexicalOverlap {
+    fn score<'a>(
+        &'a self,
+        query: &'a str,
+        texts: &'a [&'a str],
+    ) -> impl Future<Output = Result<Vec<f32>, Error>> + 'a {
+        let query: HashSet<String> = analyze(query).map(|(_, term)| term).collect();
+        let scores = texts
+            .iter()
+            .map(|text| Self::overlap(&query, text))
+            .collect();
+        std::future::ready(Ok(scores))
+    }
+}
+
+/// How reranked candidates are ordered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FinalOrder {
+    /// By the score of the reranker alone.
+    Rerank,
+    /// Scores of the first stage and of the reranker are scaled to `[0, 1]` across the candidates
+    /// and added up, weighing the reranker with `rerank_weight` and the first stage with
+    /// `1 - rerank_weight`.
+    Blend { rerank_weight: f32 },
+}
+
+/// Second stage of a search. Scores the best candidates of the first stage anew with a more
+/// expensive [`Scorer`].
+pub struct Reranker<S> {
+    scorer: S,
+    /// Number of candidates of the first stage which are reranked
+    depth: usize,
+    order: FinalOrder,
+}
+
+impl<S: Scorer> Reranker<S> {
+    pub fn new(scorer: S, depth: usize, order: FinalOrder) -> Self {
+        Self {
+            scorer,
+            depth,
+            order,
+        }
+    }
+
+    /// Number of candidates the first stage should deliver.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Reranks the first `depth` posts of `ranking`, given as tuples of post id and score, best
+    /// first. `text` returns the text scored for a post id. Returns the best `k` posts with their
+    /// new score, best first. Ties are broken in favour of the lower post id. Posts beyond `depth`
+    /// are dropped.
+    pub async fn rerank<'t>(
+        &self,
+        query: &str,
+        ranking: &[(u64, f32)],
+        text: impl Fn(u64) -> &'t str,
+        k: usize,
+    ) -> Result<Vec<(u64, f32)>, Error> {
+        let candidates = &ranking[..ranking.len().min(self.depth)];
+        let texts: Vec<&str> = candidates.iter().map(|&(id, _)| text(id)).collect();
+        let mut scores = self.scorer.score(query, &texts).await?;
+        let mut reranked: Vec<(u64, f32)> = match self.order {
+            FinalOrder::Rerank => candidates
+                .iter()
+                .zip(scores)
+                .map(|(&(id, _), score)| (id, score))
+                .collect(),
+            FinalOrder::Blend { rerank_weight } => {
+                let mut first: Vec<f32> = candidates.iter().map(|&(_, score)| score).collect();
+                min_max(&mut first);
+                min_max(&mut scores);
+                first
+                    .into_iter()
+                    .zip(scores)
+                    .zip(candidates)
+                    .map(|((first, rerank), &(id, _))| {
+                        (id, rerank_weight * rerank + (1. - rerank_weight) * first)
+                    })
+                    .collect()
+            }
+        };
+        reranked.sort_by(|(a_id, a), (b_id, b)| b.total_cmp(a).then(a_id.cmp(b_id)));
+        reranked.truncate(k);
+        Ok(reranked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FinalOrder, LexicalOverlap, Reranker, Scorer};
+
+    fn text(id: u64) -> &'static str {
+        match id {
+            1 => "How to print with PLA?",
+            2 => "Minimum layer height of a 3D printer",
+            3 => "Which layer height for miniatures?",
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn lexical_overlap_counts_shared_query_terms() {
+        let scores = LexicalOverlap
+            .score("minimum layer height", &[text(1), text(2), text(3)])
+            .await
+            .unwrap();
+
+        assert_eq!(vec![0., 1., 2. / 3.], scores);
+    }
+
+    #[tokio::test]
+    async fn rerank_reorders_candidates_within_depth() {
+        let ranking = [(1, 0.9), (3, 0.8), (2, 0.7)];
+        let reranker = Reranker::new(LexicalOverlap, 2, FinalOrder::Rerank);
+
+        let reranked = reranker
+            .rerank("minimum layer height", &ranking, text, 3)
+            .await
+            .unwrap();
+
+        // Post 2 is beyond the depth, so it is not considered
+        assert_eq!(vec![(3, 2. / 3.), (1, 0.)], reranked);
+    }
+
+    #[tokio::test]
+    async fn blend_first_stage_and_rerank_scores() {
+        let ranking = [(1, 0.9), (3, 0.8), (2, 0.5)];
+        let reranker = Reranker::new(LexicalOverlap, 3, FinalOrder::Blend { rerank_weight: 0.6 });
+
+        let reranked = reranker
+            .rerank("minimum layer height", &ranking, text, 3)
+            .await
+            .unwrap();
+
+        let ids: Vec<_> = reranked.iter().map(|(id, _)| *id).collect();
+        // 3 fits both stages well, 2 only the reranker and 1 only the first stage
+        assert_eq!(vec![3, 2, 1], ids);
+    }
+}
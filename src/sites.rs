@@ -0,0 +1,96 @@
+use crate::hybrid::min_max;
+
+/// How the scores of rankings from different communities are made comparable before merging them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Normalization {
+    /// Scores are merged as they are. Fine for similarities computed with the same model and
+    /// representations.
+    Raw,
+    /// Scores of each community are scaled, so its best one is `1` and its worst one is `0`. Every
+    /// community contributes its best question to the top, even if it does not fit well.
+    MinMax,
+    /// Scores of each community are expressed in standard deviations from the mean score of that
+    /// community, so questions standing out in their community come first.
+    #[default]
+    ZScore,
+}
+
+impl Normalization {
+    fn apply(self, scores: &mut [f32]) {
+        if scores.is_empty() {
+            return;
+        }
+        match self {
+            Normalization::Raw => (),
+            Normalization::MinMax => min_max(scores),
+            Normalization::ZScore => {
+                let n = scores.len() as f32;
+                let mean = scores.iter().sum::<f32>() / n;
+                let variance = scores.iter().map(|s| (s - mean) * (s - mean)).sum::<f32>() / n;
+                let deviation = variance.sqrt();
+                for score in scores {
+                    *score = if deviation > 0. {
+                        (*score - mean) / deviation
+                    } else {
+                        0.
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Merges the rankings of several communities, each given as tuples of post id and score, best
+/// first. Scores are normalized per community. Returns the best `k` as tuples of the index of the
+/// community in `rankings`, post id and normalized score. Ties are broken in favour of the lower
+/// community index, then the lower post id.
+pub fn merge_sites(
+    rankings: &[Vec<(u64, f32)>],
+    normalization: Normalization,
+    k: usize,
+) -> Vec<(usize, u64, f32)> {
+    let mut merged = Vec::new();
+    for (site, ranking) in rankings.iter().enumerate() {
+        let mut scores: Vec<f32> = ranking.iter().map(|&(_, score)| score).collect();
+        normalization.apply(&mut scores);
+        merged.extend(
+            ranking
+                .iter()
+                .zip(scores)
+                .map(|(&(id, _), score)| (site, id, score)),
+        );
+    }
+    merged.sort_by(|(a_site, a_id, a), (b_site, b_id, b)| {
+        b.total_cmp(a).then((a_site, a_id).cmp(&(b_site, b_id)))
+    });
+    merged.truncate(k);
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_sites, Normalization};
+
+    #[test]
+    fn raw_scores_interleave_sites() {
+        let rankings = vec![vec![(1, 0.9), (2, 0.5)], vec![(1, 0.7)]];
+
+        let merged = merge_sites(&rankings, Normalization::Raw, 3);
+
+        assert_eq!(vec![(0, 1, 0.9), (1, 1, 0.7), (0, 2, 0.5)], merged);
+    }
+
+    #[test]
+    fn z_score_prefers_outstanding_questions() {
+        // The best question of the first site barely stands out, the one of the second does.
+        let rankings = vec![
+            vec![(1, 0.82), (2, 0.81), (3, 0.80)],
+            vec![(4, 0.80), (5, 0.50), (6, 0.49)],
+        ];
+
+        let merged = merge_sites(&rankings, Normalization::ZScore, 1);
+
+        assert_eq!(1, merged[0].0);
+        assert_eq!(4, merged[0].1);
+    }
+}
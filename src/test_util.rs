@@ -0,0 +1,32 @@
+use rand::Rng;
+
+use crate::{Embedding, Embeddings, Record, EMBEDDING_SIZE};
+
+/// Each component drawn uniformly from `-1..1`.
+pub(crate) fn random_embedding(rng: &mut impl Rng) -> Embedding {
+    Embedding(std::array::from_fn(|_| rng.gen_range(-1f32..1.)))
+}
+
+/// `n` random embeddings with the post ids `0..n`.
+pub(crate) fn random_embeddings(rng: &mut impl Rng, n: usize) -> Embeddings {
+    Embeddings::from_vec(
+        (0..n as u64)
+            .map(|id| Record {
+                id,
+                text_hash: 0,
+                embedding: random_embedding(rng),
+            })
+            .collect(),
+    )
+}
+
+/// Record whose embedding starts with the components of `direction`, followed by zeros.
+pub(crate) fn record(id: u64, direction: &[f32]) -> Record {
+    let mut embedding = [0.; EMBEDDING_SIZE];
+    embedding[..direction.len()].copy_from_slice(direction);
+    Record {
+        id,
+        text_hash: id,
+        embedding: Embedding(embedding),
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    embedding::EMBEDDING_SIZE,
+    kmeans::{kmeans, nearest, SplitMix64},
+    Document, Embeddings,
+};
+
+/// Parameters controlling how questions are clustered into topics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopicParams {
+    /// Number of topics. Fewer, if there are fewer questions. No topics at all, if it is `0`.
+    pub topics: usize,
+    /// Number of k-means iterations.
+    pub iterations: usize,
+    /// Number of the most frequent tags kept for each topic.
+    pub top_tags: usize,
+}
+
+impl Default for TopicParams {
+    fn default() -> Self {
+        Self {
+            topics: 20,
+            iterations: 20,
+            top_tags: 5,
+        }
+    }
+}
+
+/// Questions about the same topic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topic {
+    /// Post ids of the questions, as tuples of post id and distance to the center of the topic.
+    /// Closest first, so the first questions are the most typical for the topic.
+    pub questions: Vec<(u64, f32)>,
+    /// Most frequent tags of the questions, with the number of questions carrying them. Most
+    /// frequent first, ties ordered by name.
+    pub tags: Vec<(String, usize)>,
+}
+
+/// Clusters questions into topics using k-means over their embeddings. A question with several
+/// embeddings is placed by its first one, which starts with the title. Degenerate embeddings are
+/// skipped. `documents` provide the tags of the questions. Largest topic first.
+///
+/// Uses a fixed seed, so clustering the same embeddings twice yields the same topics.
+pub fn cluster_topics(
+    embeddings: &Embeddings,
+    documents: &[Document],
+    params: TopicParams,
+) -> Vec<Topic> {
+    if params.topics == 0 {
+        return Vec::new();
+    }
+    let mut seen = HashSet::new();
+    let records: Vec<_> = embeddings
+        .records()
+        .iter()
+        .filter(|record| !record.embedding.is_degenerate() && seen.insert(record.id))
+        .collect();
+    let points: Vec<f32> = records
+        .iter()
+        .flat_map(|record| record.embedding.0)
+        .collect();
+    let mut rng = SplitMix64::new(0x9e3779b97f4a7c15);
+    let centroids = kmeans(
+        &points,
+        EMBEDDING_SIZE,
+        params.topics,
+        params.iterations,
+        &mut rng,
+    );
+
+    let mut topics = vec![Vec::new(); centroids.len() / EMBEDDING_SIZE];
+    for (record, point) in records.iter().zip(points.chunks_exact(EMBEDDING_SIZE)) {
+        let topic = nearest(&centroids, EMBEDDING_SIZE, point);
+        let centroid = &centroids[topic * EMBEDDING_SIZE..(topic + 1) * EMBEDDING_SIZE];
+        let distance = centroid
+            .iter()
+            .zip(point)
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f32>()
+            .sqrt();
+        topics[topic].push((record.id, distance));
+    }
+
+    let tags: HashMap<u64, &[String]> = documents
+        .iter()
+        .map(|document| (document.id, document.tags.as_slice()))
+        .collect();
+    let mut topics: Vec<Topic> = topics
+        .into_iter()
+        .filter(|questions| !questions.is_empty())
+        .map(|mut questions| {
+            questions.sort_by(|(a_id, a), (b_id, b)| a.total_cmp(b).then(a_id.cmp(b_id)));
+            let mut counts: HashMap<&str, usize> = HashMap::new();
+            for (id, _) in &questions {
+                for tag in tags.get(id).copied().unwrap_or_default() {
+                    *counts.entry(tag).or_default() += 1;
+                }
+            }
+            let mut counts: Vec<_> = counts.into_iter().collect();
+            counts.sort_by(|(a_tag, a), (b_tag, b)| b.cmp(a).then(a_tag.cmp(b_tag)));
+            let tags = counts
+                .into_iter()
+                .take(params.top_tags)
+                .map(|(tag, count)| (tag.to_owned(), count))
+                .collect();
+            Topic { questions, tags }
+        })
+        .collect();
+    topics.sort_by(|a, b| {
+        b.questions
+            .len()
+            .cmp(&a.questions.len())
+            .then(a.questions[0].0.cmp(&b.questions[0].0))
+    });
+    topics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cluster_topics, TopicParams};
+    use crate::{test_util::record, Chunking, Document, Embeddings};
+
+    fn document(id: u64, tags: &[&str]) -> Document {
+        Document {
+            tags: tags.iter().map(|&tag| tag.to_owned()).collect(),
+            ..Document::new(id, format!("Question {id}"), "", Chunking::whole())
+        }
+    }
+
+    #[test]
+    fn group_similar_questions_and_count_their_tags() {
+        let embeddings = Embeddings::from_vec(vec![
+            record(1, &[1., 0.1]),
+            record(2, &[1., 0.]),
+            record(3, &[1., -0.1]),
+            record(4, &[0., 1.]),
+            record(5, &[0.1, 1.]),
+        ]);
+        let documents = [
+            document(1, &["safety", "fumes"]),
+            document(2, &["safety"]),
+            document(3, &["safety", "enclosure"]),
+            document(4, &["bed-leveling"]),
+            document(5, &["bed-leveling"]),
+        ];
+        let params = TopicParams {
+            topics: 2,
+            ..TopicParams::default()
+        };
+
+        let topics = cluster_topics(&embeddings, &documents, params);
+
+        assert_eq!(2, topics.len());
+        let mut ids: Vec<_> = topics[0].questions.iter().map(|&(id, _)| id).collect();
+        // The question in the middle is closest to the center
+        assert_eq!(2, ids[0]);
+        ids.sort_unstable();
+        assert_eq!(vec![1, 2, 3], ids);
+        assert_eq!(("safety".to_owned(), 3), topics[0].tags[0]);
+        assert_eq!(vec![("bed-leveling".to_owned(), 2)], topics[1].tags);
+    }
+    #[test]
+    fn no_topics() {
+        let embeddings = Embeddings::from_vec(vec![record(1, &[1., 0.]), record(2, &[0., 1.])]);
+        let documents = [document(1, &[]), document(2, &[])];
+        let params = TopicParams {
+            topics: 0,
+            ..TopicParams::default()
+        };
+
+        let topics = cluster_topics(&embeddings, &documents, params);
+
+        assert!(topics.is_empty());
+    }
+}
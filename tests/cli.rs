@@ -1,7 +1,7 @@
 use assert_cmd::Command;
 use dotenv::dotenv;
 use lazy_static::lazy_static;
-use predicates::str::contains;
+use predicates::{prelude::PredicateBooleanExt, str::contains};
 
 lazy_static! {
     static ref AA_API_TOKEN: String = {
@@ -32,3 +32,294 @@ fn best_question() {
         .success()
         .stdout(contains("Is 3D printing safe for your health?"));
 }
+
+#[test]
+fn switch_document_representation_of_existing_cache() {
+    // Own copy of the posts, so the cache next to them starts out empty
+    let dir = tempfile::tempdir().unwrap();
+    let posts_xml = dir.path().join("Posts.xml");
+    std::fs::copy("tests/small-posts.xml", &posts_xml).unwrap();
+    let search = |representation: &str| {
+        Command::cargo_bin("search-stack-exchange")
+            .unwrap()
+            .args(["question", "--token", &AA_API_TOKEN])
+            .args(["--document-representation", representation])
+            .args(["--index", "ivf-pq"])
+            .arg(&posts_xml)
+            .arg("Is 3D Printing dangereous?")
+            .assert()
+    };
+
+    search("symmetric").success();
+    let assert = search("document");
+
+    assert
+        .success()
+        .stdout(contains("Is 3D printing safe for your health?"))
+        .stderr(contains("Embed all questions anew."))
+        // The index has been built for the embeddings of the other representation
+        .stderr(contains("Build IVF-PQ index"));
+}
+
+#[test]
+fn accepted_answer_of_best_question() {
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .args([
+            "answer",
+            "--token",
+            &AA_API_TOKEN,
+            "tests/small-posts.xml",
+            "Is 3D Printing dangereous?",
+        ])
+        .assert();
+
+    assert
+        .success()
+        .stdout(contains("Is 3D printing safe for your health?"))
+        .stdout(contains("Answer with score 22, accepted"))
+        .stdout(contains("Almost all 3D printers have issues").not());
+}
+
+#[test]
+fn top_three_questions() {
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .args([
+            "question",
+            "--token",
+            &AA_API_TOKEN,
+            "--top",
+            "3",
+            "tests/small-posts.xml",
+            "Is 3D Printing dangereous?",
+        ])
+        .assert();
+
+    let output = assert.success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(3, lines.len());
+    assert!(lines[0].ends_with("Is 3D printing safe for your health?"));
+}
+
+#[test]
+fn lexical_search() {
+    // Lexical search does not call the API, so any token will do
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .args([
+            "question",
+            "--token",
+            "unused",
+            "--mode",
+            "lexical",
+            "tests/small-posts.xml",
+            "minimum layer height",
+        ])
+        .assert();
+
+    assert.success().stdout(contains(
+        "How important is the minimum layer height on a 3d printer?",
+    ));
+}
+
+#[test]
+fn keyword_search_without_token() {
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .env_remove("AA_API_TOKEN")
+        .args([
+            "search",
+            "tests/small-posts.xml",
+            "\"layer height\" AND -resin",
+        ])
+        .assert();
+
+    assert.success().stdout(contains(
+        "How important is the minimum layer height on a 3d printer?",
+    ));
+}
+
+#[test]
+fn reject_unknown_community() {
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .args([
+            "multi",
+            "--token",
+            "unused",
+            "--posts",
+            "3dprinting=tests/small-posts.xml",
+            "--site",
+            "electronics",
+            "Is a heated bed safe?",
+        ])
+        .assert();
+
+    assert
+        .failure()
+        .stderr(contains("There is no community named 'electronics'"));
+}
+
+#[test]
+fn rerank_by_lexical_overlap() {
+    // Neither lexical search nor lexical reranking call the API
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .args([
+            "question",
+            "--token",
+            "unused",
+            "--mode",
+            "lexical",
+            "--rerank-with",
+            "lexical",
+            "tests/small-posts.xml",
+            "minimum layer height",
+        ])
+        .assert();
+
+    // All words of the query are contained in the title
+    assert.success().stdout(contains(
+        "1.000\tHow important is the minimum layer height on a 3d printer?",
+    ));
+}
+
+#[test]
+fn rerank_every_question_shown_beyond_depth() {
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .args([
+            "question",
+            "--token",
+            "unused",
+            "--mode",
+            "lexical",
+            "--rerank-with",
+            "lexical",
+            "--rerank-depth",
+            "1",
+            "--top",
+            "3",
+            "tests/small-posts.xml",
+            "printer layer height",
+        ])
+        .assert();
+
+    let output = assert.success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    let scores: Vec<f32> = output
+        .lines()
+        .map(|line| line.split('\t').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(2, scores.len());
+    // Lexical overlap ranges from 0 to 1, unlike BM25 scores of the first stage
+    assert!(scores.iter().all(|score| (0. ..=1.).contains(score)));
+    assert!(scores.windows(2).all(|pair| pair[0] >= pair[1]));
+}
+
+#[test]
+fn filter_by_tag() {
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .args([
+            "question",
+            "--token",
+            "unused",
+            "--mode",
+            "lexical",
+            "--top",
+            "3",
+            "--tag",
+            "safety",
+            "tests/small-posts.xml",
+            "printer prints",
+        ])
+        .assert();
+
+    let output = assert.success().get_output().stdout.clone();
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(1, lines.len());
+    assert!(lines[0].ends_with("Is 3D printing safe for your health?"));
+}
+
+#[test]
+fn show_language_of_questions() {
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .args([
+            "question",
+            "--token",
+            "unused",
+            "--mode",
+            "lexical",
+            "--language",
+            "eng",
+            "--show-language",
+            "tests/small-posts.xml",
+            "printer health",
+        ])
+        .assert();
+
+    assert
+        .success()
+        .stdout(contains("\teng\tIs 3D printing safe for your health?"))
+        .stderr(contains("Your question is in"));
+}
+
+#[test]
+fn page_through_results_with_cursor() {
+    let search = |page: &[&str]| {
+        let output = Command::cargo_bin("search-stack-exchange")
+            .unwrap()
+            .args([
+                "question", "--token", "unused", "--mode", "lexical", "--top", "1",
+            ])
+            .args(page)
+            .args(["tests/small-posts.xml", "printer"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+
+    let (first, stderr) = search(&[]);
+    let cursor = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("Next page: --cursor "))
+        .unwrap();
+    let (second, _) = search(&["--cursor", cursor]);
+
+    assert_ne!(first, second);
+    assert_eq!(search(&["--offset", "1"]).0, second);
+}
+
+#[test]
+fn explain_boosted_scores() {
+    let assert = Command::cargo_bin("search-stack-exchange")
+        .unwrap()
+        .args([
+            "question",
+            "--token",
+            "unused",
+            "--mode",
+            "lexical",
+            "--boost",
+            "--explain",
+            "tests/small-posts.xml",
+            "minimum layer height",
+        ])
+        .assert();
+
+    assert
+        .success()
+        .stdout(contains(
+            "How important is the minimum layer height on a 3d printer?",
+        ))
+        .stdout(contains("accepted 0.100"));
+}
@@ -1,7 +1,7 @@
 use aleph_alpha_client::{Client, Prompt, SemanticRepresentation, TaskSemanticEmbedding};
 use dotenv::dotenv;
 use lazy_static::lazy_static;
-use search_stack_exchange::{Embedding, Embeddings, Post, PostReader};
+use search_stack_exchange::{Embedding, Embeddings, Post, PostReader, Representation};
 
 lazy_static! {
     static ref AA_API_TOKEN: String = {
@@ -59,13 +59,17 @@ async fn find_best_question() {
     // is one of the smaller ones.
     let mut reader = PostReader::new(posts).unwrap();
     while let Some(post) = reader.next_post().unwrap() {
-        if let Post::Question { title, .. } = post {
-            titles.push(title);
+        if let Post::Question { id, title, .. } = post {
+            titles.push((id, title));
         }
     }
-    let title_embeddings = Embeddings::from_texts(&client, titles.iter().map(|s| s.as_str()))
-        .await
-        .unwrap();
+    let title_embeddings = Embeddings::from_texts(
+        &client,
+        titles.iter().map(|(id, title)| (*id, title.as_str())),
+        Representation::Symmetric,
+    )
+    .await
+    .unwrap();
     let embed_question = TaskSemanticEmbedding {
         prompt: Prompt::from_text(question),
         representation: SemanticRepresentation::Symmetric,
@@ -78,13 +82,48 @@ async fn find_best_question() {
         .embedding;
     let question = Embedding::try_from_slice(question).unwrap();
 
-    let pos_answer = title_embeddings.find_most_similar(&question);
-    let best_question = &titles[pos_answer];
+    let pos_answer = title_embeddings
+        .find_most_similar(&question)
+        .unwrap()
+        .unwrap();
+    let (best_id, best_question) = &titles[pos_answer];
 
     // Then
+    assert_eq!(2, *best_id);
+    assert_eq!(title_embeddings.id(pos_answer), *best_id);
     assert_eq!("Is 3D printing safe for your health?", best_question);
 }
 
+#[tokio::test]
+async fn find_best_question_with_asymmetric_embeddings() {
+    let client = Client::new(&AA_API_TOKEN).unwrap();
+    let mut titles = Vec::new();
+    let mut reader = PostReader::new(SMALL_POSTS).unwrap();
+    while let Some(post) = reader.next_post().unwrap() {
+        if let Post::Question { id, title, .. } = post {
+            titles.push((id, title));
+        }
+    }
+
+    let title_embeddings = Embeddings::from_texts(
+        &client,
+        titles.iter().map(|(id, title)| (*id, title.as_str())),
+        Representation::Document,
+    )
+    .await
+    .unwrap();
+    let question =
+        Embedding::from_text(&client, "Is 3D Printing dangereous?", Representation::Query)
+            .await
+            .unwrap();
+    let pos_answer = title_embeddings
+        .find_most_similar(&question)
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(2, title_embeddings.id(pos_answer));
+}
+
 #[test]
 fn count_all_answers_in_small_posts() {
     let mut reader = PostReader::new(SMALL_POSTS).unwrap();